@@ -1,8 +1,8 @@
-use actix_web::{HttpRequest, web};
+use actix_web::HttpRequest;
 use std::net::IpAddr;
 use std::sync::Arc;
 
-use crate::config::{AuthConfig, Config};
+use crate::config::AuthConfig;
 use crate::models::AppState;
 
 pub async fn is_authorized(
@@ -29,22 +29,18 @@ pub async fn is_authorized(
 }
 
 fn check_token_auth(req: &HttpRequest, auth_config: &AuthConfig) -> bool {
-    if let Some(auth_header) = req.headers().get("Authorization") {
-        if let Ok(auth_str) = auth_header.to_str() {
-            if auth_str.starts_with("Bearer ") {
-                let token = &auth_str[7..];
-                return auth_config.allowed_tokens.contains(&token.to_string());
-            }
-        }
+    if let Some(token) = extract_bearer_token(req) {
+        return auth_config.allowed_tokens.contains(&token);
     }
     
     // Also check query parameter
     if let Some(query_string) = req.uri().query() {
         for pair in query_string.split('&') {
-            if let Some((key, value)) = pair.split_once('=') {
-                if key == "token" && auth_config.allowed_tokens.contains(&value.to_string()) {
-                    return true;
-                }
+            if let Some((key, value)) = pair.split_once('=')
+                && key == "token"
+                && auth_config.allowed_tokens.contains(&value.to_string())
+            {
+                return true;
             }
         }
     }
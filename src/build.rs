@@ -1,18 +1,49 @@
-use actix_web::web::{self, Data};
+use actix_web::web;
 use chrono::Utc;
-use serde_json::json;
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::path::PathBuf;
 use std::process::Stdio;
 use std::sync::Arc;
-use tokio::io::{AsyncBufReadExt, BufReader};
+use std::time::Duration;
+use tokio::io::AsyncReadExt;
 use tokio::process::Command;
-use tokio::sync::{Mutex, broadcast};
 
+use crate::config::ProjectConfig;
 use crate::models::{
-    AppState, BuildLog, BuildNextMessage, BuildProcess, BuildResult, BuildStatus, LogLevel,
-    ServerMessage,
+    AppState, BuildLog, BuildProcess, BuildResult, BuildStatus, BuildVariables, CancellationToken,
+    CleanupCommandResult, CleanupReport, LogLevel, PersistedQueue, ProjectState, QueueEntry,
+    RunningBuild, SocketMessage, StepOutcome, StepResult,
 };
-use crate::utils::{self, read_output_lines};
+use crate::utils::{self, OutputCapture, read_output_lines};
+
+/// Time a timed out or aborted command gets between SIGTERM and SIGKILL
+const KILL_GRACE_PERIOD: Duration = Duration::from_secs(10);
+
+/// Bytes of stdout and stderr a cleanup command returns
+const MAX_CLEANUP_OUTPUT: usize = 64 * 1024;
+
+/// Time the `run_on_failure` commands of a build that ran out of `max_build_seconds` get
+const TIMED_OUT_HOOKS_LIMIT: Duration = Duration::from_secs(300);
+
+#[derive(Debug)]
+enum CommandError {
+    Failed(String),
+    Terminated,
+    TimedOut(u64),
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CommandError::Failed(message) => write!(f, "{}", message),
+            CommandError::Terminated => write!(f, "Build is terminated"),
+            CommandError::TimedOut(seconds) => {
+                write!(f, "Command timed out after {} seconds", seconds)
+            }
+        }
+    }
+}
 
 pub struct BuildManager;
 
@@ -22,37 +53,39 @@ impl BuildManager {
         let project_state = projects.get(&project_name).unwrap().clone();
         drop(projects);
 
-        let mut is_first_run = true;
-
         let project_config = state.config.projects.get(&project_name).unwrap();
 
         println!("Processing build queue for project: {}", project_name);
 
         loop {
-            println!("Processing build queue for project234: {}", project_name);
+            // Wait for a free build slot, only `max_parallel_projects` projects build at once.
+            // The slots are closed when the server shuts down, the queue is left as it is
+            let Ok(_build_slot) = state.build_slots.clone().acquire_owned().await else {
+                return;
+            };
+
+            // A cleanup running in the project path finishes before the next build starts
+            let workspace = project_state.workspace_lock.lock().await;
 
             // Check if we can start a new build
             let mut current_builds = project_state.current_build.lock().await;
             if current_builds.is_some() {
-                break; //if some build is running, dont start a new one,
-                //this is never goinng to happen, because the build manager is singleton
-                // Put back in queue and wait
-                // let mut queue = project_state.build_queue.lock().await;
-                // queue.insert(0, build_request);
-                // drop(queue);
-                // drop(current_builds);
+                //this is never goinng to happen, because each project has only one queue worker
+                break;
             }
 
             let mut queue = project_state.build_queue.lock().await;
             if queue.is_empty() {
-                break;
+                // Stop while still holding the queue lock, so a build pushed right after
+                // this sees the worker as stopped and spawns a new one
+                let mut is_queue_running = project_state.is_queue_running.write().await;
+                println!("Stopping build queue for project: {}", project_name);
+                *is_queue_running = false;
+                return;
             } //if queue is emtpy, stop the queue processing completely
-            // no build is running,
-            //then start the build process imedaitely without adding it in queue,
-
-            //if its running already,adding it in queue and leave it there
 
             let build_request = queue.remove(0);
+            Self::publish_queue(&state, &project_state, &project_name, &queue).await;
             drop(queue);
 
             // Create build process
@@ -67,49 +100,91 @@ impl BuildManager {
                 socket_token: build_request.socket_token.clone(),
                 logs: Vec::new(),
                 handle: None,
+                child_pid: None,
+                cancel_token: CancellationToken::new(),
+                send_to_sock: true,
+                steps: Vec::new(),
+                variables: utils::build_variables(
+                    &project_config.api.build.payload,
+                    &build_request,
+                ),
+                request: build_request.clone(),
+                artifacts: Vec::new(),
             };
+            let cancel_token = build_process.cancel_token.clone();
 
+            // Sockets opened while the build was queued keep listening on the same channel
+            state
+                .websocket_manager
+                .open_channel(&build_request.socket_token)
+                .await;
+            let started_message = SocketMessage::Status {
+                build_id: build_request.id.clone(),
+                status: BuildStatus::Running,
+            };
+            state
+                .websocket_manager
+                .send_message(&build_request.socket_token, &started_message)
+                .await;
+            state.publish_event(
+                &project_state,
+                &project_name,
+                &SocketMessage::BuildStarted {
+                    build_id: build_process.id.clone(),
+                    unique_id: build_process.unique_id.clone(),
+                    total_steps: build_process.total_steps,
+                },
+            );
             *current_builds = Some(build_process);
-            // *current_builds.insert(build_request.id.clone(), build_process);
             drop(current_builds);
+            drop(workspace);
+            Self::persist_queue(&state, &project_name, &project_state).await;
 
             // Start build execution
             let state_clone = state.clone();
             let project_name_clone = project_name.clone();
             let build_id = build_request.id.clone();
-            // let build_id_clone = build_id.clone();
             let build_request_for_spawn = build_request.clone();
 
             println!("Starting the build: {}", project_name);
 
-            // let state_clone = Arc::clone(&state); // Assuming state is Arc<MyState>
-            tokio::spawn(async move {
-                // Self::test(state_clone, project_name_clone,build_id, build_request_for_spawn).await;
+            let task = tokio::spawn(async move {
                 Self::execute_build(
                     state_clone,
                     project_name_clone,
                     build_id,
                     build_request_for_spawn,
-                    is_first_run,
+                    cancel_token,
                 )
                 .await;
-            })
-            .await
-            .unwrap();
+            });
+            if let Some(build) = project_state.current_build.lock().await.as_mut() {
+                build.handle = Some(task.abort_handle());
+            }
 
-            is_first_run = false;
+            match task.await {
+                // Only a shutdown aborts the task, the build stays recorded as running
+                // so it is restored as interrupted
+                Err(e) if e.is_cancelled() => return,
+                Err(e) => log::error!("Build task for project {} failed: {}", project_name, e),
+                Ok(()) => {}
+            }
+            utils::remove_spooled_files(&build_request.files).await;
 
             let mut current_builds = project_state.current_build.lock().await;
             *current_builds = None;
+            drop(current_builds);
+            Self::persist_queue(&state, &project_name, &project_state).await;
 
-            state.build_sender.send(ServerMessage::Shutdown);
+            // Only the sockets of the build that just ended are closed
+            state
+                .websocket_manager
+                .close_channel(&build_request.socket_token)
+                .await;
         } //loop
 
-        {
-            let mut is_queue_running = state.is_queue_running.write().await;
-            println!("Stopping build queue");
-            *is_queue_running = false;
-        }
+        let mut is_queue_running = project_state.is_queue_running.write().await;
+        *is_queue_running = false;
     }
 
     async fn execute_build(
@@ -117,122 +192,754 @@ impl BuildManager {
         project_name: String,
         build_id: String,
         build_request: crate::models::BuildRequest,
-        is_first_build: bool,
+        cancel_token: CancellationToken,
     ) {
         let project_config = state.config.projects.get(&project_name).unwrap().clone();
         let projects = state.projects.read().await;
         let project_state = projects.get(&project_name).unwrap().clone();
         drop(projects);
-        let mut success = true;
-        let mut step = 1;
-
-        // Execute commands
-        for command_config in &project_config.build.commands {
-            let resolved_command =
-                utils::resolve_command(&command_config.command, &build_request.payload);
-
-            //Self::send_log(
-            //    &state,
-            //    &build_request.socket_token,
-            //    &project_state,
-            //    &build_id,
-            //    step,
-            //    LogLevel::Info,
-            //    format!("Executing: {}", command_config.title),
-            //    Some(resolved_command.clone()),
-            //)
-            //.await;
 
-            {
-                let mut is_terminated = state.is_terminated.lock().await;
-                if *is_terminated {
-                    println!("Build is terminated");
-                    //send logs in here then again start the next project
-                    *is_terminated = false;
-                    success = false;
-                    break;
+        // Whole build limit, expiry kills the running command like an abort does
+        let build_timer = (project_config.build.max_build_seconds > 0).then(|| {
+            let cancel_token = cancel_token.clone();
+            let limit = Duration::from_secs(project_config.build.max_build_seconds);
+            tokio::spawn(async move {
+                tokio::time::sleep(limit).await;
+                cancel_token.time_out();
+            })
+        });
+
+        // Uploaded files have to be in place before the first command
+        let status = match Self::write_build_files(&state, &project_config, &build_request).await {
+            Ok(written) => {
+                for path in written {
+                    Self::send_log(
+                        &state,
+                        &project_state,
+                        &build_id,
+                        0,
+                        LogLevel::Info,
+                        format!("Wrote file {}", path.display()),
+                        None,
+                    )
+                    .await;
                 }
+
+                // Execute commands
+                Self::run_commands(
+                    &state,
+                    &project_state,
+                    &build_id,
+                    &build_request,
+                    &project_config.build.commands,
+                    1,
+                    &cancel_token,
+                )
+                .await
             }
+            Err(err) => {
+                Self::send_log(
+                    &state,
+                    &project_state,
+                    &build_id,
+                    0,
+                    LogLevel::Error,
+                    err,
+                    None,
+                )
+                .await;
+                BuildStatus::Failed
+            }
+        };
 
-            let result = Self::execute_command(&state, &resolved_command).await;
+        // Execute success/failure commands, an aborted build runs neither
+        let post_commands = match status {
+            BuildStatus::Success => &project_config.build.run_on_success,
+            BuildStatus::Failed | BuildStatus::TimedOut => &project_config.build.run_on_failure,
+            _ => &Vec::new(),
+        };
 
-            match result {
-                Ok(output) => {
-                    println!("One command is done in here");
-                }
-                Err(error) => {
-                    success = false;
-                    break;
+        if !post_commands.is_empty() {
+            Self::set_variable(&project_state, &build_id, "status", format!("{:?}", status)).await;
+
+            // The token of a build that ran out of time is already cancelled, its failure
+            // commands get a fresh one with a limit of their own, which aborts still reach
+            let post_token = if cancel_token.is_timed_out() {
+                CancellationToken::new()
+            } else {
+                cancel_token.clone()
+            };
+            let post_timer = cancel_token.is_timed_out().then(|| {
+                let post_token = post_token.clone();
+                tokio::spawn(async move {
+                    tokio::time::sleep(TIMED_OUT_HOOKS_LIMIT).await;
+                    post_token.time_out();
+                })
+            });
+
+            let first_step = project_config.build.commands.len() + 1;
+            {
+                let mut current_build = project_state.current_build.lock().await;
+                if let Some(build) = current_build.as_mut() {
+                    build.total_steps = first_step + post_commands.len() - 1;
+                    build.cancel_token = post_token.clone();
                 }
             }
 
-            step += 1;
+            let post_status = Self::run_commands(
+                &state,
+                &project_state,
+                &build_id,
+                &build_request,
+                post_commands,
+                first_step,
+                &post_token,
+            )
+            .await;
+
+            if let Some(post_timer) = post_timer {
+                post_timer.abort();
+            }
+            if !matches!(post_status, BuildStatus::Success) {
+                log::error!("Post build commands of build {} failed", build_id);
+            }
+        }
+
+        if let Some(build_timer) = build_timer {
+            build_timer.abort();
+        }
+
+        if let Err(err) = Self::remove_build_files(&state, &project_config, &build_request).await {
+            Self::send_log(
+                &state,
+                &project_state,
+                &build_id,
+                0,
+                LogLevel::Warning,
+                err,
+                None,
+            )
+            .await;
         }
 
-        // Execute success/failure commands
-        let post_commands = if success {
-            &project_config.build.run_on_success
-            //handle error or failure in here for the build
+        // Aborting or running out of time during the post build commands still counts
+        let status = if cancel_token.is_timed_out() {
+            BuildStatus::TimedOut
+        } else if cancel_token.is_cancelled() {
+            BuildStatus::Aborted
         } else {
-            &project_config.build.run_on_failure
+            status
+        };
+
+        if matches!(status, BuildStatus::Success) && !project_config.build.artifacts.is_empty() {
+            Self::collect_artifacts(&state, &project_name, &project_state, &build_id).await;
+        }
+
+        Self::finalize_build(
+            state.clone(),
+            &project_state,
+            status,
+            &project_config,
+            &build_request,
+        )
+        .await;
+    }
+
+    /// Copies the artifacts of a successful build into its store, a failed copy is only logged
+    async fn collect_artifacts(
+        state: &web::Data<AppState>,
+        project_name: &str,
+        project_state: &ProjectState,
+        build_id: &str,
+    ) {
+        let project_config = state.config.projects.get(project_name).unwrap();
+        let workdir = utils::resolve_project_path(
+            &state.config.base_path,
+            &project_config.build.project_path,
+        );
+        let store = utils::artifact_dir(state.config.project_log_path(project_name), build_id);
+        let patterns = project_config.build.artifacts.clone();
+
+        let collected = tokio::task::spawn_blocking(move || {
+            utils::collect_artifacts(&workdir, &patterns, &store)
+        })
+        .await
+        .unwrap_or_else(|e| Err(format!("Artifact collection stopped: {}", e)));
+
+        let artifacts = match collected {
+            Ok(artifacts) => artifacts,
+            Err(err) => {
+                Self::send_log(
+                    state,
+                    project_state,
+                    build_id,
+                    0,
+                    LogLevel::Error,
+                    err,
+                    None,
+                )
+                .await;
+                return;
+            }
         };
+
+        if artifacts.is_empty() {
+            let message = "No artifact matched the artifacts patterns".to_string();
+            Self::send_log(
+                state,
+                project_state,
+                build_id,
+                0,
+                LogLevel::Warning,
+                message,
+                None,
+            )
+            .await;
+        }
+        for artifact in &artifacts {
+            let message = format!(
+                "Collected artifact {} ({} bytes, sha256 {})",
+                artifact.name, artifact.size, artifact.sha256
+            );
+            Self::send_log(
+                state,
+                project_state,
+                build_id,
+                0,
+                LogLevel::Info,
+                message,
+                None,
+            )
+            .await;
+        }
+
+        let mut current_build = project_state.current_build.lock().await;
+        if let Some(build) = current_build.as_mut()
+            && build.id == build_id
+        {
+            build.artifacts = artifacts;
+        }
+    }
+
+    /// Writes the files uploaded with the build request to their configured paths
+    async fn write_build_files(
+        state: &AppState,
+        project_config: &ProjectConfig,
+        build_request: &crate::models::BuildRequest,
+    ) -> Result<Vec<PathBuf>, String> {
+        let workdir = utils::resolve_project_path(
+            &state.config.base_path,
+            &project_config.build.project_path,
+        );
+        let mut written = Vec::new();
+
+        for file in &project_config.api.build.file {
+            let Some(spooled) = build_request.files.get(&file.name) else {
+                continue;
+            };
+            let content = tokio::fs::read(spooled)
+                .await
+                .map_err(|e| format!("Failed to read file {}: {}", file.name, e))?;
+            let path = utils::resolve_upload_path(&workdir, &file.path)?;
+            if let Some(parent) = path.parent() {
+                tokio::fs::create_dir_all(parent)
+                    .await
+                    .map_err(|e| format!("Failed to create {}: {}", parent.display(), e))?;
+            }
+            tokio::fs::write(&path, content)
+                .await
+                .map_err(|e| format!("Failed to write file {}: {}", file.name, e))?;
+            written.push(path);
+        }
+
+        Ok(written)
+    }
+
+    /// Deletes the uploaded files whose `on_err_suc` is not "keep", whatever the build outcome
+    async fn remove_build_files(
+        state: &AppState,
+        project_config: &ProjectConfig,
+        build_request: &crate::models::BuildRequest,
+    ) -> Result<(), String> {
+        let workdir = utils::resolve_project_path(
+            &state.config.base_path,
+            &project_config.build.project_path,
+        );
+
+        for file in &project_config.api.build.file {
+            if file.on_err_suc == "keep" || !build_request.files.contains_key(&file.name) {
+                continue;
+            }
+            let path = utils::resolve_upload_path(&workdir, &file.path)?;
+            match tokio::fs::remove_file(&path).await {
+                Ok(()) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(format!("Failed to delete file {}: {}", file.name, e)),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Runs the commands one by one starting at `first_step`, following each command's
+    /// `on_error` policy. Returns the status the build ends with
+    async fn run_commands(
+        state: &actix_web::web::Data<AppState>,
+        project_state: &ProjectState,
+        build_id: &str,
+        build_request: &crate::models::BuildRequest,
+        commands: &[crate::config::CommandConfig],
+        first_step: usize,
+        cancel_token: &CancellationToken,
+    ) -> BuildStatus {
+        let project_config = state
+            .config
+            .projects
+            .get(&build_request.project_name)
+            .unwrap();
+        let mut status = BuildStatus::Success;
+
+        for (step, command_config) in (first_step..).zip(commands.iter()) {
+            if cancel_token.is_timed_out() {
+                return BuildStatus::TimedOut;
+            }
+            if cancel_token.is_cancelled() {
+                println!("Build is terminated");
+                return BuildStatus::Aborted;
+            }
+
+            // Read every step, so values set by earlier steps are available
+            let variables = Self::build_variables(project_state).await;
+            let resolved_command = utils::resolve_command(
+                &command_config.command,
+                &build_request.payload,
+                &variables.values,
+            );
+
+            {
+                let mut current_build = project_state.current_build.lock().await;
+                if let Some(build) = current_build.as_mut() {
+                    build.send_to_sock = command_config.send_to_sock;
+                    let step_message = SocketMessage::StepStarted {
+                        build_id: build_id.to_string(),
+                        step,
+                        title: command_config.title.clone(),
+                        total_steps: build.total_steps,
+                    };
+                    state
+                        .websocket_manager
+                        .send_message(&build.socket_token, &step_message)
+                        .await;
+                    state.publish_event(project_state, &build.project_name, &step_message);
+                }
+            }
+
+            Self::send_log(
+                state,
+                project_state,
+                build_id,
+                step,
+                LogLevel::Info,
+                format!("Executing: {}", command_config.title),
+                Some(resolved_command.clone()),
+            )
+            .await;
+
+            let on_error = command_config.on_error.as_str();
+            let mut attempts = 0;
+            let result = loop {
+                attempts += 1;
+                let result = match Self::prepare_command(
+                    &state.config,
+                    project_config,
+                    command_config,
+                    &resolved_command,
+                    &variables.env,
+                ) {
+                    Ok(process) => {
+                        Self::execute_command(
+                            state,
+                            project_state,
+                            build_id,
+                            process,
+                            step,
+                            command_config,
+                            cancel_token,
+                        )
+                        .await
+                    }
+                    Err(error) => Err(error),
+                };
+
+                if result.is_ok()
+                    || on_error != "retry"
+                    || attempts > command_config.retries
+                    || cancel_token.is_cancelled()
+                {
+                    break result;
+                }
+
+                Self::send_log(
+                    state,
+                    project_state,
+                    build_id,
+                    step,
+                    LogLevel::Warning,
+                    format!(
+                        "Attempt {} of {} failed, retrying in {} seconds",
+                        attempts,
+                        command_config.retries + 1,
+                        command_config.retry_delay
+                    ),
+                    Some(resolved_command.clone()),
+                )
+                .await;
+
+                tokio::select! {
+                    _ = tokio::time::sleep(Duration::from_secs(command_config.retry_delay)) => {}
+                    _ = cancel_token.cancelled() => {}
+                }
+            };
+
+            let (outcome, error) = match result {
+                Ok(captured) => {
+                    for (name, value) in captured {
+                        Self::send_log(
+                            state,
+                            project_state,
+                            build_id,
+                            step,
+                            LogLevel::Info,
+                            format!("Captured {} = {}", name, value),
+                            None,
+                        )
+                        .await;
+                        Self::capture_variable(project_state, build_id, &name, value).await;
+                    }
+                    (StepOutcome::Success, None)
+                }
+                Err(error) => {
+                    log::error!("Build {} failed at step {}: {}", build_id, step, error);
+                    let outcome = if cancel_token.is_timed_out() {
+                        StepOutcome::TimedOut
+                    } else if cancel_token.is_cancelled() {
+                        StepOutcome::Aborted
+                    } else if on_error == "ignore" {
+                        StepOutcome::Ignored
+                    } else if matches!(error, CommandError::TimedOut(_)) {
+                        StepOutcome::TimedOut
+                    } else {
+                        StepOutcome::Failed
+                    };
+                    (outcome, Some(error.to_string()))
+                }
+            };
+
+            let (level, message) = match outcome {
+                StepOutcome::Success => (
+                    LogLevel::Success,
+                    format!("Step {} finished: {}", step, command_config.title),
+                ),
+                StepOutcome::Ignored => (
+                    LogLevel::Info,
+                    format!("Step {} failed, ignored: {}", step, command_config.title),
+                ),
+                _ if on_error == "continue" || on_error == "fail_after" => (
+                    LogLevel::Warning,
+                    format!(
+                        "Step {} failed, continuing: {}",
+                        step,
+                        error.clone().unwrap_or_default()
+                    ),
+                ),
+                _ => (
+                    LogLevel::Error,
+                    format!(
+                        "Step {} failed: {}",
+                        step,
+                        error.clone().unwrap_or_default()
+                    ),
+                ),
+            };
+            Self::send_log(
+                state,
+                project_state,
+                build_id,
+                step,
+                level,
+                message,
+                Some(resolved_command),
+            )
+            .await;
+
+            Self::record_step(
+                state,
+                project_state,
+                build_id,
+                StepResult {
+                    step,
+                    title: command_config.title.clone(),
+                    outcome: outcome.clone(),
+                    attempts,
+                    error,
+                },
+            )
+            .await;
+
+            match outcome {
+                StepOutcome::Success | StepOutcome::Ignored => {}
+                StepOutcome::Aborted => return BuildStatus::Aborted,
+                _ if cancel_token.is_timed_out() => return BuildStatus::TimedOut,
+                _ => match on_error {
+                    "continue" => {}
+                    // Keep running the remaining steps, the build still ends as failed
+                    "fail_after" => status = BuildStatus::Failed,
+                    _ if matches!(outcome, StepOutcome::TimedOut) => return BuildStatus::TimedOut,
+                    _ => return BuildStatus::Failed,
+                },
+            }
+        }
+
+        status
+    }
+
+    async fn record_step(
+        state: &AppState,
+        project_state: &ProjectState,
+        build_id: &str,
+        step_result: StepResult,
+    ) {
+        let mut current_build = project_state.current_build.lock().await;
+        if let Some(build) = current_build.as_mut()
+            && build.id == build_id
+        {
+            let step_message = SocketMessage::StepFinished {
+                build_id: build_id.to_string(),
+                result: step_result.clone(),
+            };
+            state
+                .websocket_manager
+                .send_message(&build.socket_token, &step_message)
+                .await;
+            state.publish_event(project_state, &build.project_name, &step_message);
+            build.steps.push(step_result);
+        }
     }
 
     async fn execute_command(
         state: &actix_web::web::Data<AppState>,
-        command: &str,
+        project_state: &ProjectState,
+        build_id: &str,
+        mut process: Command,
         step: usize,
-    ) -> Result<String, String> {
-        let mut child = Command::new("bash")
-            .arg("-c")
-            .arg(command)
+        command_config: &crate::config::CommandConfig,
+        cancel_token: &CancellationToken,
+    ) -> Result<Vec<(String, String)>, CommandError> {
+        if cancel_token.is_cancelled() {
+            return Err(CommandError::Terminated);
+        }
+
+        let timeout_seconds = command_config.timeout_seconds;
+        let mut capture = OutputCapture::new(command_config).map_err(CommandError::Failed)?;
+
+        // Own process group, so a timeout or abort also reaches everything the shell started
+        let mut child = process
             .stdout(Stdio::piped())
             .stderr(Stdio::piped())
+            .process_group(0)
+            .kill_on_drop(true)
             .spawn()
-            .map_err(|e| format!("Failed to spawn command: {}", e))?;
-        {
-            let is_terminated = state.is_terminated.lock().await;
-            if *is_terminated {
-                println!("Build is terminated");
-                child.kill().await.unwrap();
-                return Err("Child is killed".to_string());
-            }
-        }
+            .map_err(|e| CommandError::Failed(format!("Failed to spawn command: {}", e)))?;
+
+        let pid = child.id();
+        Self::set_child_pid(project_state, build_id, pid).await;
 
         let stdout = child.stdout.take();
         let stderr = child.stderr.take();
-        tokio::join!(
-            read_output_lines(stdout, step, "success", state), //read_output_lines(stdout, step, "running", &state),
-            read_output_lines(stderr, step, "error", state) //read_output_lines(stdout, step, "running", &state),
-        );
+        let run = async {
+            tokio::join!(
+                read_output_lines(
+                    stdout,
+                    step,
+                    LogLevel::Info,
+                    state,
+                    project_state,
+                    build_id,
+                    capture.as_mut()
+                ),
+                read_output_lines(
+                    stderr,
+                    step,
+                    LogLevel::Error,
+                    state,
+                    project_state,
+                    build_id,
+                    None
+                )
+            );
+            child.wait().await
+        };
+        let mut run = Box::pin(run);
 
-        {
-            //need to check here
-            let mut running_command_child: tokio::sync::MutexGuard<
-                '_,
-                Option<tokio::process::Child>,
-            > = state.running_command_child.lock().await;
-            *running_command_child = Some(child);
-        }
-
-        let child_arc = state.running_command_child.clone();
-        let mut child_arc = child_arc.lock().await;
-        let mut child = child_arc.take();
-        drop(child_arc);
-
-        // *running_command_child = Some(child);
-        if let Some(child_opt) = child.as_mut() {
-            let status = child_opt
-                .wait()
-                .await
-                .map_err(|e| format!("Failed to wait for command: {}", e))?;
-            if status.success() {
-                Ok("".to_string())
+        let timeout = async {
+            if timeout_seconds > 0 {
+                tokio::time::sleep(Duration::from_secs(timeout_seconds)).await;
             } else {
-                Err(format!("Command exited with status: {}", status))
+                std::future::pending::<()>().await;
             }
-        } else {
-            Err(format!("No running command"))
+        };
+
+        // Only this build's own token can interrupt the command
+        let result = tokio::select! {
+            status = &mut run => Ok(status),
+            _ = cancel_token.cancelled() => Err(CommandError::Terminated),
+            _ = timeout => Err(CommandError::TimedOut(timeout_seconds)),
+        };
+
+        let result = match result {
+            Ok(status) => {
+                drop(run);
+                let status = status.map_err(|e| {
+                    CommandError::Failed(format!("Failed to wait for command: {}", e))
+                })?;
+                if status.success() {
+                    Ok(capture.map(OutputCapture::finish).unwrap_or_default())
+                } else {
+                    Err(CommandError::Failed(format!(
+                        "Command exited with status: {}",
+                        status
+                    )))
+                }
+            }
+            Err(error) => {
+                log::info!("Stopping command of build {}: {}", build_id, error);
+                Self::stop_process_groups(pid.as_slice(), run).await;
+                Err(error)
+            }
+        };
+
+        Self::set_child_pid(project_state, build_id, None).await;
+        result
+    }
+
+    /// Prepares the process of a build step: its shell, working directory and environment
+    fn prepare_command(
+        config: &crate::config::Config,
+        project_config: &crate::config::ProjectConfig,
+        command_config: &crate::config::CommandConfig,
+        command: &str,
+        payload_env: &HashMap<String, String>,
+    ) -> Result<Command, CommandError> {
+        let build_config = &project_config.build;
+        let workdir = utils::resolve_project_path(&config.base_path, &build_config.project_path);
+        if !workdir.is_dir() {
+            return Err(CommandError::Failed(format!(
+                "Project path does not exist: {}",
+                workdir.display()
+            )));
+        }
+
+        let shell = command_config
+            .shell
+            .as_deref()
+            .or(build_config.shell.as_deref())
+            .unwrap_or("bash");
+
+        let mut process = match shell {
+            "bash" | "sh" => {
+                let mut process = Command::new(shell);
+                process.arg("-c").arg(command);
+                process
+            }
+            // Run the program directly, without any shell in between
+            "none" => {
+                let argv = utils::split_command(command).map_err(CommandError::Failed)?;
+                let Some((program, args)) = argv.split_first() else {
+                    return Err(CommandError::Failed("Command is empty".to_string()));
+                };
+                let mut process = Command::new(program);
+                process.args(args);
+                process
+            }
+            other => {
+                return Err(CommandError::Failed(format!(
+                    "Unsupported shell: {}",
+                    other
+                )));
+            }
+        };
+
+        process.current_dir(&workdir);
+
+        // Without an allow-list commands get the server's whole environment
+        if let Some(inherit_env) = &build_config.inherit_env
+            && !inherit_env.iter().any(|name| name == "*")
+        {
+            process.env_clear();
+            for name in inherit_env {
+                if let Ok(value) = std::env::var(name) {
+                    process.env(name, value);
+                }
+            }
+        }
+
+        // Configured values win over the payload, command level ones over project level ones
+        process
+            .envs(payload_env)
+            .envs(&build_config.env)
+            .envs(&command_config.env);
+
+        Ok(process)
+    }
+
+    async fn build_variables(project_state: &ProjectState) -> BuildVariables {
+        let current_build = project_state.current_build.lock().await;
+        current_build
+            .as_ref()
+            .map(|build| build.variables.clone())
+            .unwrap_or_default()
+    }
+
+    /// Stores a runtime value of the build, usable as `${name}` by the steps after it
+    pub async fn set_variable(
+        project_state: &ProjectState,
+        build_id: &str,
+        name: &str,
+        value: String,
+    ) {
+        let mut current_build = project_state.current_build.lock().await;
+        if let Some(build) = current_build.as_mut()
+            && build.id == build_id
+        {
+            build.variables.values.insert(name.to_string(), value);
+        }
+    }
+
+    /// Stores a value captured from a step's output, it is also reported in the `BuildResult`
+    async fn capture_variable(
+        project_state: &ProjectState,
+        build_id: &str,
+        name: &str,
+        value: String,
+    ) {
+        let mut current_build = project_state.current_build.lock().await;
+        if let Some(build) = current_build.as_mut()
+            && build.id == build_id
+        {
+            build
+                .variables
+                .values
+                .insert(name.to_string(), value.clone());
+            build.variables.captured.insert(name.to_string(), value);
+        }
+    }
+
+    async fn set_child_pid(project_state: &ProjectState, build_id: &str, pid: Option<u32>) {
+        let mut current_build = project_state.current_build.lock().await;
+        if let Some(build) = current_build.as_mut()
+            && build.id == build_id
+        {
+            build.child_pid = pid;
         }
     }
 
@@ -245,106 +952,618 @@ impl BuildManager {
         message: String,
         command: Option<String>,
     ) {
-        let log = BuildLog {
+        let mut log = BuildLog {
+            seq: 0,
             timestamp: Utc::now(),
             step,
             level: level.clone(),
-            message: message.clone(),
+            message,
             command,
+            hidden: false,
         };
 
         // Add to build logs
         let mut current_build = project_state.current_build.lock().await;
-        if let Some(build) = current_build.as_mut() {
-            build.logs.push(log.clone());
-            build.current_step = step;
+        let Some(build) = current_build.as_mut() else {
+            return;
+        };
+        log.seq = build.logs.len() as u64 + 1;
+        log.message = build.variables.mask(&log.message);
+        log.command = log.command.map(|command| build.variables.mask(&command));
+        log.hidden = !build.send_to_sock;
+        build.logs.push(log.clone());
+        build.current_step = step;
+
+        if !build.send_to_sock {
+            return;
         }
+
+        // Send to WebSocket while the build is still locked, so a socket connecting
+        // meanwhile gets every line exactly once, from its replay or from here
+        let ws_message = SocketMessage::Log {
+            build_id: build_id.to_string(),
+            log,
+        };
+
+        state
+            .websocket_manager
+            .send_message(&build.socket_token, &ws_message)
+            .await;
+
+        state.publish_event(project_state, &build.project_name, &ws_message);
         drop(current_build);
+    }
 
-        // Send to WebSocket
-        let ws_message = json!({
-            "type": "log",
-            "build_id": build_id,
-            "step": step,
-            "level": level,
-            "message": message,
-            "timestamp": log.timestamp
-        });
+    /// Takes a build out of the queue, its sockets get the aborted result and are closed
+    pub async fn remove_queued_build(
+        state: &AppState,
+        project_state: &ProjectState,
+        project_name: &str,
+        queue: &mut Vec<crate::models::BuildRequest>,
+        pos: usize,
+    ) -> crate::models::BuildRequest {
+        let build = queue.remove(pos);
+        Self::publish_queue(state, project_state, project_name, queue).await;
+        utils::remove_spooled_files(&build.files).await;
 
-        state.build_sender.send(ServerMessage::Data(
-            serde_json::to_string(&ws_message).unwrap(),
-        ));
+        let finished_message = SocketMessage::BuildFinished {
+            build_id: build.id.clone(),
+            unique_id: build.unique_id.clone(),
+            status: BuildStatus::Aborted,
+            duration_seconds: 0,
+        };
+        state
+            .websocket_manager
+            .send_message(&build.socket_token, &finished_message)
+            .await;
+        state
+            .websocket_manager
+            .close_channel(&build.socket_token)
+            .await;
 
-        println!("Sending log to websocket: {}", ws_message.to_string());
+        build
+    }
 
-        // state.websocket_manager.send_message(socket_token, &ws_message.to_string()).await;
+    /// Aborts the queued or running build the socket token belongs to, false when there is none
+    pub async fn abort_by_token(state: &AppState, project_name: &str, token: &str) -> bool {
+        let projects = state.projects.read().await;
+        let Some(project_state) = projects.get(project_name) else {
+            return false;
+        };
+
+        let mut queue = project_state.build_queue.lock().await;
+        if let Some(pos) = queue.iter().position(|build| build.socket_token == token) {
+            Self::remove_queued_build(state, project_state, project_name, &mut queue, pos).await;
+            drop(queue);
+            Self::persist_queue(state, project_name, project_state).await;
+            return true;
+        }
+        drop(queue);
+
+        let current_build = project_state.current_build.lock().await;
+        match current_build.as_ref() {
+            Some(build) if build.socket_token == token => {
+                build.cancel_token.cancel();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Writes the project's queue and running build to disk. Must be called
+    /// without holding the current build or queue lock
+    pub async fn persist_queue(state: &AppState, project_name: &str, project_state: &ProjectState) {
+        let _persist_guard = project_state.persist_lock.lock().await;
+
+        let persisted = {
+            let current_build = project_state.current_build.lock().await;
+            let queue = project_state.build_queue.lock().await;
+            PersistedQueue {
+                running: current_build.as_ref().map(|build| RunningBuild {
+                    request: build.request.clone(),
+                    started_at: build.started_at,
+                }),
+                queue: queue.clone(),
+            }
+        };
+
+        if let Err(e) = utils::save_queue(&state.config.log_path, project_name, &persisted).await {
+            log::error!("Failed to save the queue of {}: {}", project_name, e);
+        }
+    }
+
+    /// Restores the queues saved before the server stopped. A build that was running then is
+    /// recorded as interrupted and, if the project asks for it, queued again first
+    pub async fn recover_builds(state: &web::Data<AppState>) {
+        for (project_name, project_config) in &state.config.projects {
+            let persisted = match utils::load_queue(&state.config.log_path, project_name) {
+                Ok(persisted) => persisted,
+                Err(e) => {
+                    log::error!("Failed to load the queue of {}: {}", project_name, e);
+                    continue;
+                }
+            };
+
+            let projects = state.projects.read().await;
+            let project_state = projects.get(project_name).unwrap().clone();
+            drop(projects);
+
+            let mut queue = persisted.queue;
+            if let Some(running) = persisted.running {
+                // It may have finished right before the server stopped
+                let finished = project_state
+                    .build_history
+                    .lock()
+                    .await
+                    .iter()
+                    .any(|result| result.id == running.request.id);
+
+                if !finished {
+                    Self::record_interrupted(state, &project_state, project_config, &running).await;
+                }
+
+                if !finished && project_config.requeue_interrupted {
+                    let mut request = running.request;
+                    request.id = uuid::Uuid::new_v4().to_string();
+                    request.created_at = Utc::now();
+                    queue.insert(0, request);
+                } else {
+                    utils::remove_spooled_files(&running.request.files).await;
+                }
+            }
+
+            for request in &queue {
+                state
+                    .websocket_manager
+                    .open_channel(&request.socket_token)
+                    .await;
+            }
+            let restart_queue = !queue.is_empty();
+            *project_state.build_queue.lock().await = queue;
+            Self::persist_queue(state, project_name, &project_state).await;
+
+            if restart_queue {
+                log::info!("Restarting the recovered queue of {}", project_name);
+                *project_state.is_queue_running.write().await = true;
+                let state = state.clone();
+                let project_name = project_name.clone();
+                tokio::spawn(async move {
+                    Self::process_queue(state, project_name).await;
+                });
+            }
+        }
+    }
+
+    async fn record_interrupted(
+        state: &AppState,
+        project_state: &ProjectState,
+        project_config: &ProjectConfig,
+        running: &RunningBuild,
+    ) {
+        let request = &running.request;
+        let completed_at = Utc::now();
+        let result = BuildResult {
+            id: request.id.clone(),
+            project_name: request.project_name.clone(),
+            unique_id: request.unique_id.clone(),
+            socket_token: request.socket_token.clone(),
+            status: BuildStatus::Interrupted,
+            started_at: running.started_at,
+            completed_at,
+            logs: Vec::new(),
+            steps: Vec::new(),
+            captured: HashMap::new(),
+            duration_seconds: (completed_at - running.started_at).num_seconds().max(0) as u64,
+            artifacts: Vec::new(),
+        };
+        log::warn!(
+            "Build {} of {} was interrupted by a restart",
+            result.id,
+            result.project_name
+        );
+
+        project_state
+            .build_history
+            .lock()
+            .await
+            .push(result.clone());
+        if let Err(e) = utils::append_history(&state.config.log_path, &result).await {
+            log::error!("Failed to record build {} in history: {}", result.id, e);
+        }
+
+        if !project_config.build.on_failure.is_empty() {
+            let variables = utils::build_variables(&project_config.api.build.payload, request);
+            utils::send_webhook(
+                &project_config.build.on_failure,
+                &result,
+                &request.payload,
+                &project_config.build.on_failure_payload,
+                &variables,
+            )
+            .await;
+        }
+    }
+
+    /// Tells the observers of the project which builds are waiting, in order,
+    /// and every queued build's sockets where it now stands
+    pub async fn publish_queue(
+        state: &AppState,
+        project_state: &ProjectState,
+        project_name: &str,
+        queue: &[crate::models::BuildRequest],
+    ) {
+        for (index, build) in queue.iter().enumerate() {
+            let queue_message = SocketMessage::QueuePosition {
+                build_id: build.id.clone(),
+                position: index + 1,
+                queue_length: queue.len(),
+            };
+            state
+                .websocket_manager
+                .send_message(&build.socket_token, &queue_message)
+                .await;
+        }
+
+        let builds = queue
+            .iter()
+            .enumerate()
+            .map(|(index, build)| QueueEntry {
+                build_id: build.id.clone(),
+                unique_id: build.unique_id.clone(),
+                position: index + 1,
+            })
+            .collect();
+
+        state.publish_event(
+            project_state,
+            project_name,
+            &SocketMessage::QueueChanged {
+                queue_length: queue.len(),
+                queue: builds,
+            },
+        );
     }
 
     async fn finalize_build(
         state: actix_web::web::Data<AppState>,
         project_state: &crate::models::ProjectState,
-        build_id: &str,
         status: BuildStatus,
         project_config: &crate::config::ProjectConfig,
         build_request: &crate::models::BuildRequest,
     ) {
         let completed_at = Utc::now();
 
-        // Remove from current builds and add to history
-        let mut current_build = project_state.current_build.lock().await;
-        if let Some(build) = current_build.take() {
-            let duration = (completed_at - build.started_at).num_seconds() as u64;
+        // Remove from current builds and add to history. The lock is released before
+        // the webhook and the log file, which can take as long as the network or disk
+        let (build, result) = {
+            let mut current_build = project_state.current_build.lock().await;
+            let Some(mut build) = current_build.take() else {
+                return;
+            };
 
             let result = BuildResult {
-                id: build.id,
+                id: build.id.clone(),
                 project_name: build.project_name.clone(),
+                unique_id: build.unique_id.clone(),
+                socket_token: build.socket_token.clone(),
                 status: status.clone(),
                 started_at: build.started_at,
                 completed_at,
-                logs: build.logs,
-                duration_seconds: duration,
+                logs: std::mem::take(&mut build.logs),
+                steps: std::mem::take(&mut build.steps),
+                captured: build.variables.captured.clone(),
+                duration_seconds: (completed_at - build.started_at).num_seconds() as u64,
+                artifacts: std::mem::take(&mut build.artifacts),
             };
 
             let mut history = project_state.build_history.lock().await;
             history.push(result.clone());
-            drop(history);
-            // Send webhook notification
-            let webhook_url = match status {
-                BuildStatus::Success => &project_config.build.on_success,
-                _ => &project_config.build.on_failure,
+            (build, result)
+        };
+
+        if let Err(e) = utils::append_history(&state.config.log_path, &result).await {
+            log::error!("Failed to record build {} in history: {}", result.id, e);
+        }
+
+        let finished_message = SocketMessage::BuildFinished {
+            build_id: result.id.clone(),
+            unique_id: result.unique_id.clone(),
+            status: status.clone(),
+            duration_seconds: result.duration_seconds,
+        };
+        state
+            .websocket_manager
+            .send_message(&build.socket_token, &finished_message)
+            .await;
+        state.publish_event(project_state, &result.project_name, &finished_message);
+
+        // Send webhook notification
+        let webhook_url = match status {
+            BuildStatus::Success => &project_config.build.on_success,
+            _ => &project_config.build.on_failure,
+        };
+
+        if !webhook_url.is_empty() {
+            let fields = match status {
+                BuildStatus::Success => &project_config.build.on_success_payload,
+                _ => &project_config.build.on_failure_payload,
             };
+            utils::send_webhook(
+                webhook_url,
+                &result,
+                &build_request.payload,
+                fields,
+                &build.variables,
+            )
+            .await;
+        }
 
-            if !webhook_url.is_empty() {
-                utils::send_webhook(webhook_url, &result, &build_request.payload).await;
+        // Save logs
+        let payload =
+            utils::public_payload(&project_config.api.build.payload, &build_request.payload);
+        let log_path = state.config.project_log_path(&result.project_name);
+        if let Err(e) = utils::save_build_logs(log_path, &result, &payload).await {
+            log::error!("Failed to save logs of build {}: {}", result.id, e);
+        } else {
+            // From here on the logs are read from the file, the history only keeps the record
+            let mut history = project_state.build_history.lock().await;
+            if let Some(entry) = history.iter_mut().rev().find(|entry| entry.id == result.id) {
+                entry.logs = Vec::new();
             }
+        }
+    }
 
-            // Save logs
-            utils::save_build_logs(&state.config.log_path, &result).await;
+    /// Stops the running build of every project, used when the server is shutting down.
+    /// The build tasks are dropped, which leaves the builds to be restored as interrupted,
+    /// and the process groups of their commands get SIGTERM, then SIGKILL after the grace period
+    pub async fn abort_all_builds(state: &AppState) {
+        // No queue worker starts another build from here on
+        state.build_slots.close();
 
-            // state.queue_sender.send(BuildNextMessage::Project(build.project_name.clone()));
+        let mut process_groups = Vec::new();
+        let projects = state.projects.read().await;
+        for (project_name, project_state) in projects.iter() {
+            let current_build = project_state.current_build.lock().await;
+            if let Some(build) = current_build.as_ref() {
+                log::info!("Aborting build {} of project {}", build.id, project_name);
+                if let Some(handle) = &build.handle {
+                    handle.abort();
+                }
+                build.cancel_token.cancel();
+                process_groups.extend(build.child_pid);
+            }
         }
+        drop(projects);
 
-        drop(current_build);
+        // Dropping a task only kills the shell it spawned, not what the shell started
+        Self::stop_process_groups(&process_groups, std::future::ready(())).await;
+    }
 
-        // Continue processing queue
-        // Self::process_queue(state.clone(), build_request.project_name.clone()).await;
+    /// Sends SIGTERM to the process groups and SIGKILLs what is left of them once they are
+    /// gone or the grace period is over. `running` keeps reading the output of the command
+    /// and reaps its shell meanwhile, a process writing its last lines to a closed pipe would die
+    async fn stop_process_groups(process_groups: &[u32], running: impl Future) {
+        for &pid in process_groups {
+            Self::signal_process_group(pid, libc::SIGTERM);
+        }
+        let exited = async {
+            while process_groups
+                .iter()
+                .any(|&pid| Self::signal_process_group(pid, 0))
+            {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        };
+        let _ =
+            tokio::time::timeout(KILL_GRACE_PERIOD, async { tokio::join!(running, exited) }).await;
+        // Processes that ignored SIGTERM are killed
+        for &pid in process_groups {
+            Self::signal_process_group(pid, libc::SIGKILL);
+        }
     }
 
-    pub async fn abort_build(
-        state: actix_web::web::Data<AppState>,
-        project_name: String,
-        payload: HashMap<String, serde_json::Value>,
-    ) {
-        // TODO: Implement build abortion logic
-        log::info!("Aborting build for project: {}", project_name);
+    /// Sends `signal` to the process group led by `pid`, false once the group is gone
+    fn signal_process_group(pid: u32, signal: libc::c_int) -> bool {
+        // SAFETY: kill only sends a signal to the group a command was spawned in
+        unsafe { libc::kill(-(pid as i32), signal) == 0 }
     }
 
+    /// Runs the cleanup commands a request selected, in order, then removes the
+    /// workspace paths of `remove_key` when the request asked for it
     pub async fn cleanup_project(
-        state: actix_web::web::Data<AppState>,
-        project_name: String,
-        payload: HashMap<String, serde_json::Value>,
-    ) {
-        // TODO: Implement cleanup logic
-        log::info!("Cleaning up project: {}", project_name);
+        state: &AppState,
+        project_name: &str,
+        commands: &[String],
+        remove_key: Option<&str>,
+        payload: &HashMap<String, serde_json::Value>,
+    ) -> CleanupReport {
+        let project_config = state.config.projects.get(project_name).unwrap();
+        let variables = utils::payload_variables(&project_config.api.cleanup.payload, payload);
+        let mut report = CleanupReport {
+            success: true,
+            ..CleanupReport::default()
+        };
+
+        for name in commands {
+            let command_config = &project_config.cleanup.commands[name];
+            let result = Self::run_cleanup_command(
+                &state.config,
+                project_config,
+                name,
+                command_config,
+                &variables,
+            )
+            .await;
+            let ignored = matches!(command_config.on_error.as_str(), "continue" | "ignore");
+            let stop = !result.success && !ignored;
+            report.success &= result.success || ignored;
+            report.commands.push(result);
+            if stop {
+                return report;
+            }
+        }
+
+        if let Some(unique_id) = remove_key {
+            match Self::remove_workspaces(&state.config, project_config, unique_id).await {
+                Ok(removed) => report.removed = removed,
+                Err(e) => {
+                    report.success = false;
+                    report.error = Some(e);
+                }
+            }
+        }
+
+        log::info!(
+            "Cleaned up project {}: {} commands, {} paths removed",
+            project_name,
+            report.commands.len(),
+            report.removed.len()
+        );
+        report
+    }
+
+    /// Runs one configured cleanup command, request values only reach it as env variables
+    async fn run_cleanup_command(
+        config: &crate::config::Config,
+        project_config: &ProjectConfig,
+        name: &str,
+        command_config: &crate::config::CommandConfig,
+        variables: &BuildVariables,
+    ) -> CleanupCommandResult {
+        let mut result = CleanupCommandResult {
+            name: name.to_string(),
+            success: false,
+            exit_code: None,
+            stdout: String::new(),
+            stderr: String::new(),
+            error: None,
+        };
+
+        let process = Self::prepare_command(
+            config,
+            project_config,
+            command_config,
+            &command_config.command,
+            &variables.env,
+        );
+        let mut child = match process.and_then(|mut process| {
+            process
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped())
+                .process_group(0)
+                .kill_on_drop(true)
+                .spawn()
+                .map_err(|e| CommandError::Failed(format!("Failed to spawn command: {}", e)))
+        }) {
+            Ok(child) => child,
+            Err(e) => {
+                result.error = Some(e.to_string());
+                return result;
+            }
+        };
+
+        let pid = child.id();
+        let stdout = child.stdout.take();
+        let stderr = child.stderr.take();
+        let mut run = Box::pin(async {
+            let (stdout, stderr) = tokio::join!(read_output(stdout), read_output(stderr));
+            (stdout, stderr, child.wait().await)
+        });
+
+        let timeout_seconds = command_config.timeout_seconds;
+        let timeout = async {
+            if timeout_seconds > 0 {
+                tokio::time::sleep(Duration::from_secs(timeout_seconds)).await;
+            } else {
+                std::future::pending::<()>().await;
+            }
+        };
+
+        let outcome = tokio::select! {
+            outcome = &mut run => Some(outcome),
+            _ = timeout => None,
+        };
+
+        match outcome {
+            Some((stdout, stderr, status)) => {
+                result.stdout = variables.mask(&stdout);
+                result.stderr = variables.mask(&stderr);
+                match status {
+                    Ok(status) => {
+                        result.success = status.success();
+                        result.exit_code = status.code();
+                    }
+                    Err(e) => result.error = Some(format!("Failed to wait for command: {}", e)),
+                }
+            }
+            None => {
+                Self::stop_process_groups(pid.as_slice(), run).await;
+                result.error = Some(CommandError::TimedOut(timeout_seconds).to_string());
+            }
+        }
+
+        result
+    }
+
+    /// Removes the configured cleanup paths of one build key, missing ones are skipped
+    async fn remove_workspaces(
+        config: &crate::config::Config,
+        project_config: &ProjectConfig,
+        unique_id: &str,
+    ) -> Result<Vec<String>, String> {
+        let workdir =
+            utils::resolve_project_path(&config.base_path, &project_config.build.project_path);
+        let values = HashMap::from([
+            (
+                project_config.build.unique_build_key.clone(),
+                unique_id.to_string(),
+            ),
+            ("unique_id".to_string(), unique_id.to_string()),
+        ]);
+        let mut removed = Vec::new();
+
+        for template in &project_config.cleanup.remove {
+            let relative = utils::interpolate(template, &values);
+            let path = utils::resolve_upload_path(&workdir, &relative)?;
+
+            // A symlink is removed itself, never what it points to
+            let result = match tokio::fs::symlink_metadata(&path).await {
+                Ok(meta) if meta.is_dir() => tokio::fs::remove_dir_all(&path).await,
+                Ok(_) => tokio::fs::remove_file(&path).await,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => Err(e),
+            };
+            result.map_err(|e| format!("Failed to remove {}: {}", relative, e))?;
+            removed.push(relative);
+        }
+
+        Ok(removed)
     }
 }
+
+/// Output of a cleanup command, only its last `MAX_CLEANUP_OUTPUT` bytes are kept.
+/// What falls out of that window is dropped while reading, never buffered
+async fn read_output(stream: Option<impl tokio::io::AsyncRead + Unpin>) -> String {
+    let Some(mut stream) = stream else {
+        return String::new();
+    };
+    let mut output = VecDeque::with_capacity(MAX_CLEANUP_OUTPUT);
+    let mut chunk = [0u8; 8192];
+    loop {
+        match stream.read(&mut chunk).await {
+            Ok(0) => break,
+            Ok(read) => {
+                // The chunk is smaller than the window, only older output has to make room
+                let overflow = (output.len() + read).saturating_sub(MAX_CLEANUP_OUTPUT);
+                output.drain(..overflow);
+                output.extend(&chunk[..read]);
+            }
+            Err(e) => {
+                log::warn!("Failed to read cleanup command output: {}", e);
+                break;
+            }
+        }
+    }
+
+    String::from_utf8_lossy(output.make_contiguous()).into_owned()
+}
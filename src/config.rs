@@ -1,18 +1,47 @@
-use anyhow::Result;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
+use crate::validate::{self, ConfigError};
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Config {
     pub name: String,
     pub port: u16,
     pub base_path: String,
     pub log_path: String,
+    #[serde(default)]
+    pub max_parallel_projects: usize, // 0 = unlimited
+    #[serde(default = "default_janitor_interval")]
+    pub janitor_interval: u64, // seconds between two log retention sweeps
     pub ssl: SslConfig,
     pub auth: AuthConfig,
+    #[serde(default)]
+    pub signed_urls: Option<SignedUrlConfig>, // no secret, no signed download links
     pub projects: HashMap<String, ProjectConfig>,
 }
 
+fn default_janitor_interval() -> u64 {
+    3600
+}
+
+/// Download links for artifacts and logs that work without the project auth until they expire
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct SignedUrlConfig {
+    pub secret: String, // HMAC-SHA256 key, changing it invalidates every link already given out
+    #[serde(default = "default_link_ttl")]
+    pub default_ttl: u64, // seconds
+    #[serde(default = "default_max_link_ttl")]
+    pub max_ttl: u64, // seconds
+}
+
+fn default_link_ttl() -> u64 {
+    24 * 3600
+}
+
+fn default_max_link_ttl() -> u64 {
+    7 * 24 * 3600
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct SslConfig {
     pub enable_ssl: bool,
@@ -36,6 +65,36 @@ pub struct ProjectConfig {
     pub api: ApiConfig,
     pub auth: Option<AuthConfig>,
     pub build: BuildConfig,
+    #[serde(default)]
+    pub requeue_interrupted: bool, // put a build cut off by a restart back at the front of the queue
+    #[serde(default)]
+    pub log_path: Option<String>, // build log files of this project, defaults to the root log_path
+    #[serde(default)]
+    pub retention: Option<RetentionConfig>, // no retention keeps every log file
+    #[serde(default)]
+    pub cleanup: CleanupConfig,
+}
+
+/// What the cleanup endpoint may do, a request only picks among these
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct CleanupConfig {
+    #[serde(default)]
+    pub commands: HashMap<String, CommandConfig>, // selected by name with the `^cmd^` payload field
+    #[serde(default)]
+    pub remove: Vec<String>, // paths inside the project removed with `^remove^`, `${<unique_build_key>}` is the build key
+}
+
+/// Limits the janitor enforces on a project's log files, 0 disables a limit
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct RetentionConfig {
+    #[serde(default)]
+    pub keep_builds: usize, // logs of the last N builds are kept
+    #[serde(default)]
+    pub max_age_days: u64,
+    #[serde(default)]
+    pub max_total_mb: u64, // size of all the project's log files, the oldest go first
+    #[serde(default)]
+    pub compress_after_days: u64, // gzip logs older than this into <build_id>.log.gz
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -45,6 +104,14 @@ pub struct ApiConfig {
     pub abort: EndpointConfig,
     pub cleanup: EndpointConfig,
     pub socket: EndpointConfig,
+    #[serde(default)]
+    pub observe: Option<EndpointConfig>, // websocket streaming every build of the project
+    #[serde(default)]
+    pub history: Option<EndpointConfig>, // lists finished builds, <endpoint>/{build_id} returns one with its logs
+    #[serde(default)]
+    pub artifacts: Option<EndpointConfig>, // <endpoint>/{build_id} lists a build's artifacts, <endpoint>/{build_id}/{name} downloads one
+    #[serde(default)]
+    pub sign: Option<EndpointConfig>, // mints signed download links of a build's artifacts and log
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -52,7 +119,7 @@ pub struct EndpointConfig {
     pub endpoint: String,
     pub method: String,
     pub payload: Vec<String>,
-    #[serde(default)]
+    #[serde(default, alias = "return")]
     pub return_fields: Vec<ReturnField>,
     #[serde(default)]
     pub file: Vec<FileConfig>,
@@ -69,6 +136,12 @@ pub struct FileConfig {
     pub name: String,
     pub path: String,
     pub on_err_suc: String, // "del", "keep"
+    #[serde(default = "default_max_file_size")]
+    pub max_size: usize, // bytes
+}
+
+fn default_max_file_size() -> usize {
+    10 * 1024 * 1024
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -81,9 +154,19 @@ pub struct BuildConfig {
     pub on_failure_payload: Vec<String>,
     pub commands: Vec<CommandConfig>,
     #[serde(default)]
+    pub max_build_seconds: u64, // 0 = no limit
+    #[serde(default)]
+    pub shell: Option<String>, // "bash", "sh" or "none" to run without a shell
+    #[serde(default)]
+    pub env: HashMap<String, String>,
+    #[serde(default)]
+    pub inherit_env: Option<Vec<String>>, // server env variables passed to commands, unset or "*" for all
+    #[serde(default)]
     pub run_on_success: Vec<CommandConfig>,
     #[serde(default)]
     pub run_on_failure: Vec<CommandConfig>,
+    #[serde(default)]
+    pub artifacts: Vec<String>, // globs relative to project_path, collected after a successful build
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -91,16 +174,52 @@ pub struct CommandConfig {
     pub command: String,
     pub title: String,
     #[serde(default)]
-    pub on_error: String, // "abort", "continue"
+    pub on_error: String, // "abort", "continue", "retry", "ignore", "fail_after"
+    #[serde(default)]
+    pub retries: u32, // extra attempts when on_error is "retry"
+    #[serde(default)]
+    pub retry_delay: u64, // seconds to wait between retries
+    #[serde(default)]
+    pub timeout_seconds: u64, // 0 = no limit
+    #[serde(default)]
+    pub shell: Option<String>, // overrides the project shell
+    #[serde(default)]
+    pub env: HashMap<String, String>,
+    #[serde(default)]
+    pub capture: Option<String>, // variable name the output is stored in, "*" takes every marker
+    #[serde(default)]
+    pub capture_mode: String, // "last_line", "regex", "marker"
+    #[serde(default)]
+    pub capture_regex: Option<String>,
     #[serde(default)]
     pub send_to_sock: bool,
 }
 
 impl Config {
-    pub fn load(path: &str) -> Result<Self> {
-        let content = std::fs::read_to_string(path)?;
-        let config: Config = toml::from_str(&content)?;
+    /// Reads and validates the config, every problem found is returned
+    pub fn load(path: &str) -> Result<Self, Vec<ConfigError>> {
+        let content = std::fs::read_to_string(path).map_err(|e| {
+            vec![ConfigError {
+                line: None,
+                key: String::new(),
+                message: format!("Failed to read {}: {}", path, e),
+            }]
+        })?;
+        let config: Config =
+            toml::from_str(&content).map_err(|e| vec![ConfigError::parse(&content, &e)])?;
+
+        let errors = validate::validate(&config, &content);
+        if !errors.is_empty() {
+            return Err(errors);
+        }
         Ok(config)
     }
-}
 
+    /// Directory holding the build log files of a project
+    pub fn project_log_path(&self, project_name: &str) -> &str {
+        self.projects
+            .get(project_name)
+            .and_then(|project| project.log_path.as_deref())
+            .unwrap_or(&self.log_path)
+    }
+}
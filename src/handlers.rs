@@ -1,18 +1,30 @@
-use actix_web::{App, HttpRequest, HttpResponse, Result, delete, get, post, web};
-use chrono::Utc;
-use serde_json::json;
+use actix_multipart::Multipart;
+use actix_web::http::header::{
+    CONTENT_TYPE, ContentDisposition, DispositionParam, DispositionType,
+};
+use actix_web::web::Bytes;
+use actix_web::{App, HttpRequest, HttpResponse, HttpResponseBuilder, Result, get, web};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use chrono::{DateTime, Utc};
+use futures_util::{StreamExt, stream};
+use serde_json::{Value, json};
 use std::collections::HashMap;
-use std::sync::Arc;
+use std::path::Path;
+use tokio::io::AsyncReadExt;
 use uuid::Uuid;
 
 use crate::auth::is_authorized;
-use crate::build::{self, BuildManager};
-use crate::config::Config;
+use crate::build::BuildManager;
+use crate::config::{Config, FileConfig, ProjectConfig};
 use crate::models::{
-    AppState, BuildApiRequest, BuildApiResponse, BuildInfo, BuildRequest, BuildStatusResponse,
+    AppState, BuildApiRequest, BuildApiResponse, BuildInfo, BuildRequest, BuildResult,
+    BuildStatusResponse, HistoryQuery, SignRequest,
 };
-use crate::utils;
-use crate::websocket::websocket_handler;
+use crate::signed_url;
+use crate::sse::sse_handler;
+use crate::utils::{self, PayloadKind};
+use crate::websocket::{project_observer_handler, websocket_handler};
 
 #[get("/health")]
 pub async fn health_check() -> Result<HttpResponse> {
@@ -32,7 +44,7 @@ where
             InitError = (),
         >,
 {
-    for (_project_name, project_config) in &config.projects {
+    for project_config in config.projects.values() {
         let base_path = &project_config.base_endpoint_path;
 
         if !&project_config.api.build.endpoint.trim().is_empty() {
@@ -49,6 +61,10 @@ where
         if !&project_config.api.socket.endpoint.trim().is_empty() {
             let websocket_path = format!("{}{}", base_path, &project_config.api.socket.endpoint);
             app = app.route(&websocket_path, web::get().to(websocket_handler));
+
+            // Same stream as Server-Sent Events for clients without websocket
+            let events_path = format!("{}/events", websocket_path);
+            app = app.route(&events_path, web::get().to(sse_handler));
         }
 
         if !&project_config.api.abort.endpoint.trim().is_empty() {
@@ -60,14 +76,49 @@ where
             let cleanup_path = format!("{}{}", base_path, &project_config.api.cleanup.endpoint);
             app = app.route(&cleanup_path, web::post().to(cleanup_handler));
         }
+
+        if let Some(history) = &project_config.api.history
+            && !history.endpoint.trim().is_empty()
+        {
+            let history_path = format!("{}{}", base_path, &history.endpoint);
+            app = app.route(&history_path, web::get().to(history_handler));
+            let build_path = format!("{}/{{build_id}}", history_path);
+            app = app.route(&build_path, web::get().to(history_build_handler));
+        }
+
+        if let Some(artifacts) = &project_config.api.artifacts
+            && !artifacts.endpoint.trim().is_empty()
+        {
+            let build_path = format!("{}{}/{{build_id}}", base_path, &artifacts.endpoint);
+            app = app.route(&build_path, web::get().to(artifacts_handler));
+            let download_path = format!("{}/{{name:.*}}", build_path);
+            app = app.route(&download_path, web::get().to(artifact_download_handler));
+        }
+
+        if let Some(sign) = &project_config.api.sign
+            && !sign.endpoint.trim().is_empty()
+        {
+            let sign_path = format!("{}{}", base_path, &sign.endpoint);
+            app = app.route(&sign_path, web::post().to(sign_handler));
+        }
+
+        if let Some(observe) = &project_config.api.observe
+            && !observe.endpoint.trim().is_empty()
+        {
+            let observe_path = format!("{}{}", base_path, &observe.endpoint);
+            app = app.route(&observe_path, web::get().to(project_observer_handler));
+        }
     } //loop
 
     app
 }
 
+/// Size limit of the build request without its files
+const MAX_PAYLOAD_SIZE: usize = 256 * 1024;
+
 async fn build_handler(
     req: HttpRequest,
-    payload: web::Json<BuildApiRequest>,
+    body: web::Payload,
     state: web::Data<AppState>,
 ) -> Result<HttpResponse> {
     let project_name = extract_project_name(&req, &state.config)?;
@@ -83,9 +134,15 @@ async fn build_handler(
 
     let project_config = state.config.projects.get(&project_name).unwrap();
 
+    let (payload, files) =
+        match read_build_payload(&req, body, &project_config.api.build.file).await {
+            Ok(read) => read,
+            Err(response) => return Ok(response),
+        };
+
     // Validate payload
     for required_field in &project_config.api.build.payload {
-        let field_name = required_field.trim_start_matches('$');
+        let (field_name, _) = utils::parse_payload_field(required_field);
         if !payload.payload.contains_key(field_name) {
             return Ok(HttpResponse::BadRequest().json(BuildApiResponse {
                 success: false,
@@ -114,12 +171,10 @@ async fn build_handler(
     let projects = state.projects.read().await;
     let project_state = projects.get(&project_name).unwrap();
 
-    let unique_id = payload
-        .payload
-        .get(&project_config.build.unique_build_key)
-        .unwrap()
-        .as_str()
-        .unwrap();
+    // A number or any other JSON value is the key too, as the cleanup endpoint reads it
+    let unique_id =
+        utils::value_to_string(&payload.payload[&project_config.build.unique_build_key]);
+    let unique_id = unique_id.as_str();
     // Check if multi-build is allowed
     if !project_config.allow_multi_build {
         let current_builds = project_state.current_build.lock().await;
@@ -136,15 +191,15 @@ async fn build_handler(
         }
     } else {
         let current_builds = project_state.current_build.lock().await;
-        if let Some(build) = current_builds.as_ref() {
-            if build.project_name != project_name {
-                return Ok(HttpResponse::Conflict().json(BuildApiResponse {
-                    success: false,
-                    message: "Build already in progress for other project".to_string(),
-                    data: None,
-                    state: "already_running_other_project".to_string(),
-                }));
-            }
+        if let Some(build) = current_builds.as_ref()
+            && build.project_name != project_name
+        {
+            return Ok(HttpResponse::Conflict().json(BuildApiResponse {
+                success: false,
+                message: "Build already in progress for other project".to_string(),
+                data: None,
+                state: "already_running_other_project".to_string(),
+            }));
         } //if
 
         // Check queue limit
@@ -166,30 +221,30 @@ async fn build_handler(
 
         for i in 0..queues.len() {
             let build_req = queues.get(i);
-            if let Some(build) = build_req {
-                if &build.unique_id == unique_id {
-                    return Ok(HttpResponse::TooManyRequests().json(BuildApiResponse {
-                        success: false,
-                        message: "This build is already in pending".to_string(),
-                        state: "already".to_string(),
-                        data: Some(json!({"socket_token":build.socket_token})),
-                    }));
-                }
-            }
-        }
-        drop(queues);
-        drop(current_builds);
-        let current_build = project_state.current_build.lock().await;
-        if let Some(cur) = current_build.as_ref() {
-            if cur.unique_id == unique_id {
+            if let Some(build) = build_req
+                && build.unique_id == unique_id
+            {
                 return Ok(HttpResponse::TooManyRequests().json(BuildApiResponse {
                     success: false,
                     message: "This build is already in pending".to_string(),
                     state: "already".to_string(),
-                    data: Some(json!({"socket_token":cur.socket_token})),
+                    data: Some(json!({"socket_token":build.socket_token})),
                 }));
             }
         }
+        drop(queues);
+        drop(current_builds);
+        let current_build = project_state.current_build.lock().await;
+        if let Some(cur) = current_build.as_ref()
+            && cur.unique_id == unique_id
+        {
+            return Ok(HttpResponse::TooManyRequests().json(BuildApiResponse {
+                success: false,
+                message: "This build is already in pending".to_string(),
+                state: "already".to_string(),
+                data: Some(json!({"socket_token":cur.socket_token})),
+            }));
+        }
         drop(current_build);
     } //if multi build available
 
@@ -197,12 +252,25 @@ async fn build_handler(
     let build_id = Uuid::new_v4().to_string();
     let socket_token = utils::generate_token(32);
 
+    // Written to disk once, so saving the queue never has to write the uploads again
+    let files = match utils::spool_files(&state.config.log_path, &build_id, files).await {
+        Ok(files) => files,
+        Err(e) => {
+            log::error!("Failed to store the files of build {}: {}", build_id, e);
+            return Ok(payload_error(
+                HttpResponse::InternalServerError(),
+                "write_failed",
+                "Failed to store the uploaded files".to_string(),
+            ));
+        }
+    };
+
     // Create build request
     let build_request = BuildRequest {
         id: build_id.clone(),
         project_name: project_name.clone(),
         payload: payload.payload.clone(),
-        files: HashMap::new(), // TODO: Handle file uploads
+        files,
         created_at: Utc::now(),
         unique_id: unique_id.to_string(),
         socket_token: socket_token.clone(),
@@ -211,50 +279,54 @@ async fn build_handler(
     let mut queue = project_state.build_queue.lock().await;
 
     queue.push(build_request);
+    let mut queue_position = queue.len();
+    // Open the build's channel now, its socket can connect while it is still queued
+    state.websocket_manager.open_channel(&socket_token).await;
+    BuildManager::publish_queue(&state, project_state, &project_name, &queue).await;
     drop(queue);
+    BuildManager::persist_queue(&state, &project_name, project_state).await;
 
     let state_clone = state.clone();
 
-    {
-        let mut is_queue_running = state.is_queue_running.write().await;
+    // Start build manager if not running
+    let build_state = {
+        let mut is_queue_running = project_state.is_queue_running.write().await;
         if *is_queue_running {
-            println!("Queue is already running,added only");
+            println!("Queue is already running for {},added only", project_name);
+            "queued"
         } else {
             *is_queue_running = true;
+            let queue_project = project_name.clone();
             tokio::spawn(async move {
-                BuildManager::process_queue(state_clone, project_name.clone()).await;
+                BuildManager::process_queue(state_clone, queue_project).await;
             });
-            return Ok(HttpResponse::Ok().json(BuildApiResponse {
-                success: true,
-                state: "building".to_string(),
-                message: "Build queued successfully".to_string(),
-                data: Some(json!({
-                    "socket_token":socket_token,
-                    "build_id":build_id
-                })),
-            }));
+            queue_position = 0;
+            "building"
         }
-        drop(is_queue_running);
-    }
-    // Start build manager if not running
-
-    // handle.abort();
+    };
 
-    // Add custom return fields
-    // for return_field in &project_config.api.build.return_fields {
-    //     let value = utils::resolve_variable(&return_field.value, &payload.payload, &socket_token);
-    //     let key = return_field.name.as_ref().unwrap_or(&return_field.value);
-    //     response_data[key] = json!(value);
-    // }
+    let context = utils::ResponseContext {
+        status: build_state,
+        project_name: &project_name,
+        unique_id: Some(unique_id),
+        socket_token: Some(&socket_token),
+        build_id: Some(&build_id),
+        queue_position: Some(queue_position),
+        payload: &payload.payload,
+    };
+    let data = utils::resolve_return_fields(&project_config.api.build.return_fields, &context)
+        .unwrap_or_else(|| {
+            json!({
+                "socket_token":socket_token,
+                "build_id":build_id,
+            })
+        });
 
     Ok(HttpResponse::Ok().json(BuildApiResponse {
         success: true,
-        state: "queued".to_string(),
+        state: build_state.to_string(),
         message: "Build queued successfully".to_string(),
-        data: Some(json!({
-            "socket_token":socket_token,
-            "build_id":build_id,
-        })),
+        data: Some(data),
     }))
 }
 
@@ -277,21 +349,50 @@ async fn is_building_handler(
     let projects = state.projects.read().await;
     let project_state = projects.get(&project_name).unwrap();
 
-    let config = state.config.projects.get(&project_name);
-    if let Some(pro) = config {
-        let unique_key = &pro.build.unique_build_key;
-        if payload.payload.contains_key(unique_key) {
-            return Ok(HttpResponse::Unauthorized().json(BuildApiResponse {
-                success: false,
-                message: "Unique Key Not Found".to_string(),
-                data: None,
-                state: "unique_key_not_found".to_string(),
-            }));
-        }
-    }
+    let project_config = state.config.projects.get(&project_name).unwrap();
+    let unique_key = &project_config.build.unique_build_key;
+    let Some(unique_id) = payload.payload.get(unique_key).map(utils::value_to_string) else {
+        return Ok(HttpResponse::BadRequest().json(BuildApiResponse {
+            success: false,
+            message: "Unique Key Not Found".to_string(),
+            data: None,
+            state: "unique_key_not_found".to_string(),
+        }));
+    };
 
-    let queue = project_state.build_queue.lock().await;
+    // Same order as the queue worker, the current build before the queue
     let current_build = project_state.current_build.lock().await;
+    let queue = project_state.build_queue.lock().await;
+
+    // Where the build asked about is: running (0), waiting in the queue or unknown
+    let (status, queue_position, socket_token, build_id) = match current_build.as_ref() {
+        Some(build) if build.unique_id == *unique_id => (
+            "building",
+            Some(0),
+            Some(&build.socket_token),
+            Some(&build.id),
+        ),
+        _ => match queue.iter().position(|build| build.unique_id == *unique_id) {
+            Some(pos) => (
+                "queued",
+                Some(pos + 1),
+                Some(&queue[pos].socket_token),
+                Some(&queue[pos].id),
+            ),
+            None => ("idle", None, None, None),
+        },
+    };
+    let context = utils::ResponseContext {
+        status,
+        project_name: &project_name,
+        unique_id: Some(&unique_id),
+        socket_token: socket_token.map(String::as_str),
+        build_id: build_id.map(String::as_str),
+        queue_position,
+        payload: &payload.payload,
+    };
+    let data =
+        utils::resolve_return_fields(&project_config.api.is_building.return_fields, &context);
 
     if let Some(build) = current_build.as_ref() {
         let build_info = BuildInfo {
@@ -306,6 +407,7 @@ async fn is_building_handler(
             is_building: true,
             queue_length: queue.len(),
             current_build: Some(build_info),
+            data,
         }));
     } //if 
 
@@ -313,6 +415,7 @@ async fn is_building_handler(
         is_building: false,
         queue_length: queue.len(),
         current_build: None,
+        data,
     }))
 }
 
@@ -347,38 +450,68 @@ async fn abort_handler(
                     .get(&project_config.build.unique_build_key)
                     .unwrap()
         }) {
-            let build = queues.remove(pos); // Now safely remove it
+            let build = BuildManager::remove_queued_build(
+                &state,
+                queues_main_lock,
+                &project_name,
+                &mut queues,
+                pos,
+            )
+            .await;
+            drop(queues);
+            BuildManager::persist_queue(&state, &project_name, queues_main_lock).await;
 
+            let context = utils::ResponseContext {
+                status: "aborted",
+                project_name: &project_name,
+                unique_id: Some(&build.unique_id),
+                socket_token: Some(&build.socket_token),
+                build_id: Some(&build.id),
+                queue_position: None,
+                payload: &payload.payload,
+            };
             return Ok(HttpResponse::TooManyRequests().json(BuildApiResponse {
                 success: true,
                 message: "Project is terminated".to_string(),
                 state: "aborted".to_string(),
-                data: None,
+                data: utils::resolve_return_fields(
+                    &project_config.api.abort.return_fields,
+                    &context,
+                ),
             }));
         }
 
+        // The queue worker locks the current build before the queue, never hold both here
+        drop(queues);
+
         let current_build = queues_main_lock.current_build.lock().await;
-        if let Some(cur) = current_build.as_ref() {
-            if cur.unique_id
+        if let Some(cur) = current_build.as_ref()
+            && cur.unique_id
                 == *payload
                     .payload
                     .get(&project_config.build.unique_build_key)
                     .unwrap()
-            {
-                let mut child = state.running_command_child.lock().await.take();
-                if let Some(child) = child.as_mut() {
-                    child.kill().await.unwrap();
-
-                    let mut is_terminated = state.is_terminated.lock().await;
-                    *is_terminated = true;
-                }
-                return Ok(HttpResponse::TooManyRequests().json(BuildApiResponse {
-                    success: true,
-                    message: "This is being running already..Killing".to_string(),
-                    state: "aborted".to_string(),
-                    data: None,
-                }));
-            }
+        {
+            // The build task kills its own running command once the token is cancelled
+            cur.cancel_token.cancel();
+            let context = utils::ResponseContext {
+                status: "aborted",
+                project_name: &project_name,
+                unique_id: Some(&cur.unique_id),
+                socket_token: Some(&cur.socket_token),
+                build_id: Some(&cur.id),
+                queue_position: None,
+                payload: &payload.payload,
+            };
+            return Ok(HttpResponse::TooManyRequests().json(BuildApiResponse {
+                success: true,
+                message: "This is being running already..Killing".to_string(),
+                state: "aborted".to_string(),
+                data: utils::resolve_return_fields(
+                    &project_config.api.abort.return_fields,
+                    &context,
+                ),
+            }));
         }
         drop(current_build);
     }
@@ -386,10 +519,22 @@ async fn abort_handler(
 
     //just send aborted even if its not bulding, this is neccessary for frontend if this server
     //cant sync the state and the frontend expecting it as pending or building forever.
+    let context = utils::ResponseContext {
+        status: "aborted",
+        project_name: &project_name,
+        unique_id: payload
+            .payload
+            .get(&project_config.build.unique_build_key)
+            .and_then(Value::as_str),
+        socket_token: None,
+        build_id: None,
+        queue_position: None,
+        payload: &payload.payload,
+    };
     Ok(HttpResponse::Ok().json(BuildApiResponse {
         success: false,
         message: "No any build found".to_string(),
-        data: None,
+        data: utils::resolve_return_fields(&project_config.api.abort.return_fields, &context),
         state: "aborted".to_string(),
     }))
 }
@@ -410,17 +555,688 @@ async fn cleanup_handler(
         }));
     }
 
-    // TODO: Implement cleanup logic
-    BuildManager::cleanup_project(state.clone(), project_name, payload.payload.clone()).await;
+    let project_config = state.config.projects.get(&project_name).unwrap();
+    let (commands, remove_key) = match read_cleanup_request(project_config, &payload.payload) {
+        Ok(request) => request,
+        Err(message) => {
+            return Ok(payload_error(
+                HttpResponse::BadRequest(),
+                "invalid_cleanup",
+                message,
+            ));
+        }
+    };
+
+    let project_state = state
+        .projects
+        .read()
+        .await
+        .get(&project_name)
+        .unwrap()
+        .clone();
+    // Held until the cleanup is over, the queue worker waits for it before starting a build
+    let _workspace = project_state.workspace_lock.lock().await;
+    {
+        let current_build = project_state.current_build.lock().await;
+        let queue = project_state.build_queue.lock().await;
+
+        // The commands run in the project path, where the running build works too
+        if !commands.is_empty()
+            && let Some(build) = current_build.as_ref()
+        {
+            return Ok(payload_error(
+                HttpResponse::Conflict(),
+                "busy",
+                format!("Build {} of {} is running", build.id, build.unique_id),
+            ));
+        }
+
+        // Never pull a workspace from under a build that is running or waiting for it
+        if let Some(unique_id) = &remove_key {
+            let busy = current_build
+                .as_ref()
+                .is_some_and(|build| build.unique_id == *unique_id)
+                || queue.iter().any(|build| build.unique_id == *unique_id);
+            if busy {
+                return Ok(payload_error(
+                    HttpResponse::Conflict(),
+                    "busy",
+                    format!("A build of {} is running or queued", unique_id),
+                ));
+            }
+        }
+    }
+
+    let report = BuildManager::cleanup_project(
+        &state,
+        &project_name,
+        &commands,
+        remove_key.as_deref(),
+        &payload.payload,
+    )
+    .await;
+
+    let status = if report.success { "success" } else { "failed" };
+    let context = utils::ResponseContext {
+        status,
+        project_name: &project_name,
+        unique_id: payload
+            .payload
+            .get(&project_config.build.unique_build_key)
+            .and_then(Value::as_str),
+        socket_token: None,
+        build_id: None,
+        queue_position: None,
+        payload: &payload.payload,
+    };
+    let mut data = json!(report);
+    if let Some(Value::Object(fields)) =
+        utils::resolve_return_fields(&project_config.api.cleanup.return_fields, &context)
+    {
+        data.as_object_mut().unwrap().extend(fields);
+    }
+
+    Ok(HttpResponse::Ok().json(BuildApiResponse {
+        success: report.success,
+        message: if report.success {
+            "Cleanup completed".to_string()
+        } else {
+            "Cleanup failed".to_string()
+        },
+        data: Some(data),
+        state: status.to_string(),
+    }))
+}
+
+/// Reads which configured cleanup commands a request selected with `cmd` and
+/// the build key whose workspace it asked to `remove`
+fn read_cleanup_request(
+    project_config: &ProjectConfig,
+    payload: &HashMap<String, Value>,
+) -> Result<(Vec<String>, Option<String>), String> {
+    let accepts = |marker: &str| {
+        project_config
+            .api
+            .cleanup
+            .payload
+            .iter()
+            .any(|field| utils::parse_payload_field(field) == (marker, PayloadKind::Cleanup))
+    };
+
+    let commands = match payload.get("cmd") {
+        None | Some(Value::Null) => Vec::new(),
+        Some(_) if !accepts("cmd") => return Err("Cleanup commands are not enabled".to_string()),
+        Some(Value::String(name)) => vec![name.clone()],
+        Some(Value::Array(names)) => names
+            .iter()
+            .map(|name| name.as_str().map(str::to_string))
+            .collect::<Option<Vec<_>>>()
+            .ok_or("cmd must be a command name or a list of names")?,
+        Some(_) => return Err("cmd must be a command name or a list of names".to_string()),
+    };
+    if let Some(name) = commands
+        .iter()
+        .find(|name| !project_config.cleanup.commands.contains_key(*name))
+    {
+        return Err(format!("Unknown cleanup command: {}", name));
+    }
+
+    let remove = match payload.get("remove") {
+        None | Some(Value::Null) => false,
+        Some(_) if !accepts("remove") => return Err("Workspace removal is not enabled".to_string()),
+        Some(Value::Bool(remove)) => *remove,
+        Some(_) => return Err("remove must be true or false".to_string()),
+    };
+    let remove_key = if remove {
+        let unique_key = &project_config.build.unique_build_key;
+        let unique_id = payload
+            .get(unique_key)
+            .map(utils::value_to_string)
+            .unwrap_or_default();
+        // The key names a directory, it must not reach a parent or a sibling
+        if unique_id.is_empty()
+            || unique_id == "."
+            || unique_id == ".."
+            || unique_id.contains(['/', '\\', '\0'])
+        {
+            return Err(format!(
+                "{} must be a plain name to remove its workspace",
+                unique_key
+            ));
+        }
+        if project_config.cleanup.remove.is_empty() {
+            return Err("No cleanup paths are configured".to_string());
+        }
+        Some(unique_id)
+    } else {
+        None
+    };
+
+    if commands.is_empty() && remove_key.is_none() {
+        return Err("Nothing to clean up, send cmd or remove".to_string());
+    }
+
+    Ok((commands, remove_key))
+}
+
+/// Lists the finished builds of the project, newest first
+async fn history_handler(
+    req: HttpRequest,
+    query: web::Query<HistoryQuery>,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let project_name = extract_project_name(&req, &state.config)?;
+
+    if !is_authorized(&req, &state, Some(&project_name)).await {
+        return Ok(HttpResponse::Unauthorized().json(BuildApiResponse {
+            success: false,
+            message: "Unauthorized".to_string(),
+            state: "unauthorized".to_string(),
+            data: None,
+        }));
+    }
+
+    let page = query.page.max(1);
+    let per_page = query.per_page.clamp(1, 100);
+
+    let projects = state.projects.read().await;
+    let project_state = projects.get(&project_name).unwrap();
+    let history = project_state.build_history.lock().await;
+
+    let matching: Vec<_> =
+        history
+            .iter()
+            .rev()
+            .filter(|result| {
+                query.status.as_ref().is_none_or(|status| {
+                    format!("{:?}", result.status).eq_ignore_ascii_case(status)
+                }) && query
+                    .unique_id
+                    .as_ref()
+                    .is_none_or(|unique_id| result.unique_id == *unique_id)
+                    && query.from.is_none_or(|from| result.started_at >= from)
+                    && query.to.is_none_or(|to| result.started_at < to)
+            })
+            .collect();
+
+    // Logs are only returned for a single build
+    let total = matching.len();
+    let builds: Vec<_> = matching
+        .into_iter()
+        .skip((page - 1) * per_page)
+        .take(per_page)
+        .map(|result| BuildResult {
+            logs: Vec::new(),
+            ..result.clone()
+        })
+        .collect();
 
     Ok(HttpResponse::Ok().json(BuildApiResponse {
         success: true,
-        message: "Cleanup completed".to_string(),
-        data: None,
-        state: "success".to_string(),
+        message: "Build history".to_string(),
+        state: "history".to_string(),
+        data: Some(json!({
+            "builds": builds,
+            "total": total,
+            "page": page,
+            "per_page": per_page,
+        })),
     }))
 }
 
+/// Returns one finished build with its logs
+async fn history_build_handler(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let project_name = extract_project_name(&req, &state.config)?;
+
+    if !is_authorized(&req, &state, Some(&project_name)).await {
+        return Ok(HttpResponse::Unauthorized().json(BuildApiResponse {
+            success: false,
+            message: "Unauthorized".to_string(),
+            state: "unauthorized".to_string(),
+            data: None,
+        }));
+    }
+
+    let build_id = req.match_info().get("build_id").unwrap_or_default();
+
+    let projects = state.projects.read().await;
+    let project_state = projects.get(&project_name).unwrap();
+    let history = project_state.build_history.lock().await;
+    let Some(mut result) = history.iter().find(|result| result.id == build_id).cloned() else {
+        return Ok(HttpResponse::NotFound().json(BuildApiResponse {
+            success: false,
+            message: format!("Build not found: {}", build_id),
+            state: "not_found".to_string(),
+            data: None,
+        }));
+    };
+    drop(history);
+    drop(projects);
+
+    result.logs = utils::logs_of(&result, state.config.project_log_path(&project_name));
+
+    Ok(HttpResponse::Ok().json(BuildApiResponse {
+        success: true,
+        message: "Build found".to_string(),
+        state: "history".to_string(),
+        data: Some(json!(result)),
+    }))
+}
+
+/// Lists the artifacts a finished build produced
+async fn artifacts_handler(req: HttpRequest, state: web::Data<AppState>) -> Result<HttpResponse> {
+    let project_name = extract_project_name(&req, &state.config)?;
+
+    if !is_authorized(&req, &state, Some(&project_name)).await {
+        return Ok(HttpResponse::Unauthorized().json(BuildApiResponse {
+            success: false,
+            message: "Unauthorized".to_string(),
+            state: "unauthorized".to_string(),
+            data: None,
+        }));
+    }
+
+    let build_id = req.match_info().get("build_id").unwrap_or_default();
+    let Some(result) = finished_build(&state, &project_name, build_id).await else {
+        return Ok(payload_error(
+            HttpResponse::NotFound(),
+            "not_found",
+            format!("Build not found: {}", build_id),
+        ));
+    };
+
+    Ok(HttpResponse::Ok().json(BuildApiResponse {
+        success: true,
+        message: "Build artifacts".to_string(),
+        state: "artifacts".to_string(),
+        data: Some(json!({
+            "build_id": result.id,
+            "status": result.status,
+            "artifacts": result.artifacts,
+        })),
+    }))
+}
+
+/// Downloads one artifact of a finished build, only names recorded for the build are served
+async fn artifact_download_handler(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let project_name = extract_project_name(&req, &state.config)?;
+
+    if !is_authorized(&req, &state, Some(&project_name)).await {
+        return Ok(HttpResponse::Unauthorized().json(BuildApiResponse {
+            success: false,
+            message: "Unauthorized".to_string(),
+            state: "unauthorized".to_string(),
+            data: None,
+        }));
+    }
+
+    let build_id = req.match_info().get("build_id").unwrap_or_default();
+    let name = req.match_info().get("name").unwrap_or_default();
+    let artifact = finished_build(&state, &project_name, build_id)
+        .await
+        .and_then(|result| {
+            result
+                .artifacts
+                .into_iter()
+                .find(|artifact| artifact.name == name)
+        });
+    let Some(artifact) = artifact else {
+        return Ok(payload_error(
+            HttpResponse::NotFound(),
+            "not_found",
+            format!("Artifact not found: {}", name),
+        ));
+    };
+
+    let path = utils::artifact_dir(state.config.project_log_path(&project_name), build_id)
+        .join(&artifact.name);
+    Ok(file_response(&path, &artifact.name).await)
+}
+
+pub async fn finished_build(
+    state: &AppState,
+    project_name: &str,
+    build_id: &str,
+) -> Option<BuildResult> {
+    let projects = state.projects.read().await;
+    let history = projects.get(project_name)?.build_history.lock().await;
+    history.iter().find(|result| result.id == build_id).cloned()
+}
+
+/// Streams a file as an attachment, in chunks so large files never sit in memory
+pub async fn file_response(path: &Path, name: &str) -> HttpResponse {
+    let file = match tokio::fs::File::open(path).await {
+        Ok(file) => file,
+        Err(e) => {
+            log::error!("Failed to open {}: {}", path.display(), e);
+            return payload_error(
+                HttpResponse::NotFound(),
+                "not_found",
+                format!("File is no longer available: {}", name),
+            );
+        }
+    };
+    let size = match file.metadata().await {
+        Ok(metadata) => metadata.len(),
+        Err(e) => {
+            return payload_error(
+                HttpResponse::InternalServerError(),
+                "read_failed",
+                format!("Failed to read {}: {}", name, e),
+            );
+        }
+    };
+
+    let chunks = stream::unfold(Some(file), |file| async move {
+        let mut file = file?;
+        let mut buffer = vec![0; 64 * 1024];
+        match file.read(&mut buffer).await {
+            Ok(0) => None,
+            Ok(read) => {
+                buffer.truncate(read);
+                Some((Ok(Bytes::from(buffer)), Some(file)))
+            }
+            // End the stream after reporting the error
+            Err(e) => Some((Err(e), None)),
+        }
+    });
+
+    let file_name = Path::new(name)
+        .file_name()
+        .map(|file_name| file_name.to_string_lossy().to_string())
+        .unwrap_or_else(|| name.to_string());
+    HttpResponse::Ok()
+        .content_type("application/octet-stream")
+        .insert_header(ContentDisposition {
+            disposition: DispositionType::Attachment,
+            parameters: vec![DispositionParam::Filename(file_name)],
+        })
+        .no_chunking(size)
+        .streaming(chunks)
+}
+
+/// Mints a signed, expiring download link to an artifact or the log of a finished build
+async fn sign_handler(
+    req: HttpRequest,
+    body: web::Json<SignRequest>,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let project_name = extract_project_name(&req, &state.config)?;
+
+    if !is_authorized(&req, &state, Some(&project_name)).await {
+        return Ok(HttpResponse::Unauthorized().json(BuildApiResponse {
+            success: false,
+            message: "Unauthorized".to_string(),
+            state: "unauthorized".to_string(),
+            data: None,
+        }));
+    }
+
+    let Some(signed_urls) = &state.config.signed_urls else {
+        return Ok(payload_error(
+            HttpResponse::NotFound(),
+            "not_found",
+            "Signed links are not enabled".to_string(),
+        ));
+    };
+    let Some(result) = finished_build(&state, &project_name, &body.build_id).await else {
+        return Ok(payload_error(
+            HttpResponse::NotFound(),
+            "not_found",
+            format!("Build not found: {}", body.build_id),
+        ));
+    };
+
+    let file = match (&body.artifact, body.log) {
+        (Some(name), false)
+            if result
+                .artifacts
+                .iter()
+                .any(|artifact| artifact.name == *name) =>
+        {
+            format!("{}{}", signed_url::ARTIFACT_PREFIX, name)
+        }
+        (Some(name), false) => {
+            return Ok(payload_error(
+                HttpResponse::NotFound(),
+                "not_found",
+                format!("Artifact not found: {}", name),
+            ));
+        }
+        (None, true) => signed_url::LOG_FILE.to_string(),
+        _ => {
+            return Ok(payload_error(
+                HttpResponse::BadRequest(),
+                "invalid_request",
+                "Send either artifact or log".to_string(),
+            ));
+        }
+    };
+
+    let ttl = body
+        .expires_in
+        .unwrap_or(signed_urls.default_ttl.min(signed_urls.max_ttl));
+    if ttl == 0 || ttl > signed_urls.max_ttl {
+        return Ok(payload_error(
+            HttpResponse::BadRequest(),
+            "invalid_request",
+            format!("expires_in must be between 1 and {}", signed_urls.max_ttl),
+        ));
+    }
+    let expires = Utc::now().timestamp() + ttl as i64;
+
+    let signature = match signed_url::sign(
+        &signed_urls.secret,
+        &project_name,
+        &result.id,
+        &file,
+        expires,
+    ) {
+        Ok(signature) => signature,
+        Err(e) => {
+            log::error!("Failed to sign a link of build {}: {}", result.id, e);
+            return Ok(payload_error(
+                HttpResponse::InternalServerError(),
+                "sign_failed",
+                "Failed to sign the link".to_string(),
+            ));
+        }
+    };
+    let path = signed_url::signed_path(&project_name, &result.id, &file, expires, &signature);
+    let connection = req.connection_info();
+    let url = format!("{}://{}{}", connection.scheme(), connection.host(), path);
+
+    Ok(HttpResponse::Ok().json(BuildApiResponse {
+        success: true,
+        message: "Signed link created".to_string(),
+        state: "signed".to_string(),
+        data: Some(json!({
+            "url": url,
+            "path": path,
+            "expires_at": DateTime::from_timestamp(expires, 0),
+        })),
+    }))
+}
+
+/// Reads the build request either as JSON, where declared files are base64 strings,
+/// or as multipart/form-data, where declared files are file parts and any other part is a payload field
+async fn read_build_payload(
+    req: &HttpRequest,
+    body: web::Payload,
+    file_configs: &[FileConfig],
+) -> Result<(BuildApiRequest, HashMap<String, Vec<u8>>), HttpResponse> {
+    let is_multipart = req
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("multipart/form-data"));
+
+    if is_multipart {
+        read_multipart_payload(req, body, file_configs).await
+    } else {
+        read_json_payload(body, file_configs).await
+    }
+}
+
+async fn read_json_payload(
+    mut body: web::Payload,
+    file_configs: &[FileConfig],
+) -> Result<(BuildApiRequest, HashMap<String, Vec<u8>>), HttpResponse> {
+    // Base64 grows the files by a third
+    let limit = MAX_PAYLOAD_SIZE
+        + file_configs
+            .iter()
+            .map(|file| file.max_size / 3 * 4 + 4)
+            .sum::<usize>();
+
+    let mut bytes = Vec::new();
+    while let Some(chunk) = body.next().await {
+        let chunk = chunk.map_err(|e| {
+            payload_error(HttpResponse::BadRequest(), "invalid_payload", e.to_string())
+        })?;
+        if bytes.len() + chunk.len() > limit {
+            return Err(payload_error(
+                HttpResponse::PayloadTooLarge(),
+                "too_large",
+                "Request body is too large".to_string(),
+            ));
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+
+    let mut payload: BuildApiRequest = serde_json::from_slice(&bytes).map_err(|e| {
+        payload_error(
+            HttpResponse::BadRequest(),
+            "invalid_payload",
+            format!("Invalid JSON payload: {}", e),
+        )
+    })?;
+
+    let mut files = HashMap::new();
+    for file in file_configs {
+        let Some(value) = payload.payload.remove(&file.name) else {
+            continue;
+        };
+        let content = value
+            .as_str()
+            .and_then(|encoded| BASE64.decode(encoded).ok())
+            .ok_or_else(|| {
+                payload_error(
+                    HttpResponse::BadRequest(),
+                    "invalid_file",
+                    format!("File {} must be a base64 string", file.name),
+                )
+            })?;
+        if content.len() > file.max_size {
+            return Err(file_too_large(file));
+        }
+        files.insert(file.name.clone(), content);
+    }
+
+    Ok((payload, files))
+}
+
+async fn read_multipart_payload(
+    req: &HttpRequest,
+    body: web::Payload,
+    file_configs: &[FileConfig],
+) -> Result<(BuildApiRequest, HashMap<String, Vec<u8>>), HttpResponse> {
+    let invalid = |e: actix_multipart::MultipartError| {
+        payload_error(HttpResponse::BadRequest(), "invalid_payload", e.to_string())
+    };
+
+    let mut multipart = Multipart::new(req.headers(), body);
+    let mut payload = HashMap::new();
+    let mut files = HashMap::new();
+    let mut fields_size = 0;
+
+    while let Some(field) = multipart.next().await {
+        let mut field = field.map_err(invalid)?;
+        let name = field.name().unwrap_or_default().to_string();
+        let file = file_configs.iter().find(|file| file.name == name);
+        if file.is_none()
+            && field
+                .content_disposition()
+                .and_then(|cd| cd.get_filename())
+                .is_some()
+        {
+            return Err(payload_error(
+                HttpResponse::BadRequest(),
+                "unknown_file",
+                format!("File {} is not declared for this endpoint", name),
+            ));
+        }
+
+        let mut content = Vec::new();
+        while let Some(chunk) = field.next().await {
+            let chunk = chunk.map_err(invalid)?;
+            content.extend_from_slice(&chunk);
+            match file {
+                Some(file) if content.len() > file.max_size => return Err(file_too_large(file)),
+                None if fields_size + content.len() > MAX_PAYLOAD_SIZE => {
+                    return Err(payload_error(
+                        HttpResponse::PayloadTooLarge(),
+                        "too_large",
+                        "Request fields are too large".to_string(),
+                    ));
+                }
+                _ => {}
+            }
+        }
+
+        if file.is_some() {
+            files.insert(name, content);
+            continue;
+        }
+        fields_size += content.len();
+
+        let text = String::from_utf8(content).map_err(|_| {
+            payload_error(
+                HttpResponse::BadRequest(),
+                "invalid_payload",
+                format!("Field {} is not valid UTF-8", name),
+            )
+        })?;
+        // A part named payload may carry the whole JSON payload
+        if name == "payload"
+            && let Ok(Value::Object(object)) = serde_json::from_str(&text)
+        {
+            payload.extend(object);
+        } else {
+            payload.insert(name, Value::String(text));
+        }
+    }
+
+    Ok((BuildApiRequest { payload }, files))
+}
+
+fn file_too_large(file: &FileConfig) -> HttpResponse {
+    payload_error(
+        HttpResponse::PayloadTooLarge(),
+        "file_too_large",
+        format!("File {} is larger than {} bytes", file.name, file.max_size),
+    )
+}
+
+pub fn payload_error(
+    mut response: HttpResponseBuilder,
+    state: &str,
+    message: String,
+) -> HttpResponse {
+    response.json(BuildApiResponse {
+        success: false,
+        message,
+        data: None,
+        state: state.to_string(),
+    })
+}
+
 pub fn extract_project_name(req: &HttpRequest, config: &Config) -> Result<String> {
     let path = req.path();
 
@@ -0,0 +1,274 @@
+use actix_web::web;
+use chrono::{DateTime, Utc};
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use serde::Serialize;
+use std::collections::HashSet;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use uuid::Uuid;
+
+use crate::config::RetentionConfig;
+use crate::models::AppState;
+use crate::utils;
+
+/// A log file or artifact directory the janitor removed, appended to `<log_path>/janitor.jsonl`
+#[derive(Debug, Serialize)]
+struct Deletion {
+    deleted_at: DateTime<Utc>,
+    project: String,
+    build_id: String,
+    file: String,
+    bytes: u64,
+    reason: &'static str,
+}
+
+/// Starts the background task enforcing the log retention of every project
+pub fn start(state: web::Data<AppState>) {
+    if !state
+        .config
+        .projects
+        .values()
+        .any(|project| project.retention.is_some())
+    {
+        return;
+    }
+
+    let interval = Duration::from_secs(state.config.janitor_interval.max(1));
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            sweep(&state).await;
+        }
+    });
+}
+
+async fn sweep(state: &AppState) {
+    for (project_name, project_config) in &state.config.projects {
+        let Some(retention) = project_config.retention.clone() else {
+            continue;
+        };
+
+        // Log files are matched through the history so projects can share a log directory,
+        // the ones no project's history knows about are left to the first project to sweep
+        let (builds, known) = {
+            let projects = state.projects.read().await;
+            let Some(project_state) = projects.get(project_name) else {
+                continue;
+            };
+            let history = project_state.build_history.lock().await;
+            let builds: Vec<(String, DateTime<Utc>)> = history
+                .iter()
+                .rev()
+                .map(|result| (result.id.clone(), result.completed_at))
+                .collect();
+            drop(history);
+
+            let mut known = HashSet::new();
+            for other in projects.values() {
+                let history = other.build_history.lock().await;
+                known.extend(history.iter().map(|result| result.id.clone()));
+            }
+            (builds, known)
+        };
+
+        let log_path = state.config.project_log_path(project_name).to_string();
+        let record_path = state.config.log_path.clone();
+        let project = project_name.clone();
+        let recorded = tokio::task::spawn_blocking(move || {
+            let builds = with_untracked_logs(&log_path, builds, &known);
+            let deletions = enforce_retention(&log_path, &project, &builds, &retention);
+            record_deletions(&record_path, &deletions)
+        })
+        .await;
+
+        if let Ok(Err(e)) = recorded {
+            log::error!("Failed to record deleted logs of {}: {}", project_name, e);
+        }
+    }
+}
+
+/// Compresses and deletes the log files of `builds`, newest first, past the retention limits.
+/// The artifacts of a build are deleted along with its log
+fn enforce_retention(
+    log_path: &str,
+    project_name: &str,
+    builds: &[(String, DateTime<Utc>)],
+    retention: &RetentionConfig,
+) -> Vec<Deletion> {
+    let now = Utc::now();
+    let max_bytes = retention.max_total_mb * 1024 * 1024;
+    let mut kept = 0;
+    let mut total_bytes = 0;
+    let mut over_size = false;
+    let mut deletions = Vec::new();
+
+    for (build_id, completed_at) in builds {
+        let artifacts = utils::artifact_dir(log_path, build_id);
+        let mut log_file = existing_log_file(log_path, build_id);
+        if log_file.is_none() && !artifacts.is_dir() {
+            continue;
+        }
+        let mut bytes = log_file
+            .as_ref()
+            .and_then(|path| fs::metadata(path).ok())
+            .map_or(0, |meta| meta.len());
+        let age_days = (now - *completed_at).num_days().max(0) as u64;
+
+        if let Some(path) = &log_file
+            && retention.compress_after_days > 0
+            && age_days >= retention.compress_after_days
+            && path.extension().is_some_and(|ext| ext == "log")
+        {
+            match compress_log(path, &utils::compressed_log_file(log_path, build_id)) {
+                Ok((compressed, size)) => {
+                    log_file = Some(compressed);
+                    bytes = size;
+                }
+                Err(e) => log::warn!("Failed to compress {}: {}", path.display(), e),
+            }
+        }
+
+        over_size = over_size || (max_bytes > 0 && total_bytes + bytes > max_bytes);
+        let reason = if retention.keep_builds > 0 && kept >= retention.keep_builds {
+            Some("keep_builds")
+        } else if retention.max_age_days > 0 && age_days >= retention.max_age_days {
+            Some("max_age_days")
+        } else if over_size {
+            Some("max_total_mb")
+        } else {
+            None
+        };
+
+        let Some(reason) = reason else {
+            kept += 1;
+            total_bytes += bytes;
+            continue;
+        };
+
+        let removals = [
+            log_file.map(|path| (path, bytes)),
+            artifacts
+                .is_dir()
+                .then(|| (artifacts.clone(), dir_size(&artifacts))),
+        ];
+        for (path, bytes) in removals.into_iter().flatten() {
+            let removed = if path.is_dir() {
+                fs::remove_dir_all(&path)
+            } else {
+                fs::remove_file(&path)
+            };
+            if let Err(e) = removed {
+                log::warn!("Failed to delete {}: {}", path.display(), e);
+                continue;
+            }
+            log::info!("Deleted {} ({})", path.display(), reason);
+            deletions.push(Deletion {
+                deleted_at: now,
+                project: project_name.to_string(),
+                build_id: build_id.clone(),
+                file: path.display().to_string(),
+                bytes,
+                reason,
+            });
+        }
+    }
+
+    deletions
+}
+
+/// Bytes of every file under `path`
+fn dir_size(path: &Path) -> u64 {
+    let Ok(entries) = fs::read_dir(path) else {
+        return 0;
+    };
+    entries
+        .flatten()
+        .map(|entry| match entry.file_type() {
+            Ok(file_type) if file_type.is_dir() => dir_size(&entry.path()),
+            _ => entry.metadata().map_or(0, |meta| meta.len()),
+        })
+        .sum()
+}
+
+/// Adds the build logs in `log_path` without a history entry, aged by their modification
+/// time, and sorts all builds newest first
+fn with_untracked_logs(
+    log_path: &str,
+    mut builds: Vec<(String, DateTime<Utc>)>,
+    known: &HashSet<String>,
+) -> Vec<(String, DateTime<Utc>)> {
+    let Ok(entries) = fs::read_dir(log_path) else {
+        return builds;
+    };
+
+    let mut untracked = HashSet::new();
+    for entry in entries.flatten() {
+        let file_name = entry.file_name();
+        let Some(name) = file_name.to_str() else {
+            continue;
+        };
+        // Only files named like build logs, anything else in the directory is left alone
+        let Some(build_id) = name
+            .strip_suffix(".log.gz")
+            .or_else(|| name.strip_suffix(".log"))
+        else {
+            continue;
+        };
+        if Uuid::parse_str(build_id).is_err()
+            || known.contains(build_id)
+            || untracked.contains(build_id)
+        {
+            continue;
+        }
+        let Ok(modified) = entry.metadata().and_then(|meta| meta.modified()) else {
+            continue;
+        };
+
+        untracked.insert(build_id.to_string());
+        builds.push((build_id.to_string(), modified.into()));
+    }
+
+    builds.sort_by_key(|(_, time)| std::cmp::Reverse(*time));
+    builds
+}
+
+fn existing_log_file(log_path: &str, build_id: &str) -> Option<PathBuf> {
+    [
+        utils::build_log_file(log_path, build_id),
+        utils::compressed_log_file(log_path, build_id),
+    ]
+    .into_iter()
+    .find(|path| path.exists())
+}
+
+/// Gzips a log file next to itself and removes the original, returns the new path and size
+fn compress_log(path: &Path, compressed: &Path) -> io::Result<(PathBuf, u64)> {
+    let content = fs::read(path)?;
+    let mut encoder = GzEncoder::new(fs::File::create(compressed)?, Compression::default());
+    encoder.write_all(&content)?;
+    encoder.finish()?;
+    fs::remove_file(path)?;
+
+    Ok((compressed.to_path_buf(), fs::metadata(compressed)?.len()))
+}
+
+fn record_deletions(log_path: &str, deletions: &[Deletion]) -> io::Result<()> {
+    if deletions.is_empty() {
+        return Ok(());
+    }
+
+    fs::create_dir_all(log_path)?;
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(Path::new(log_path).join("janitor.jsonl"))?;
+    for deletion in deletions {
+        writeln!(file, "{}", serde_json::to_string(deletion)?)?;
+    }
+
+    Ok(())
+}
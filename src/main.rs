@@ -1,7 +1,6 @@
-use actix_web::{web, App, HttpServer, middleware::Logger};
+use actix_web::{web, App, HttpServer};
 use build::BuildManager;
-use std::{env, sync::Arc};
-use tokio::sync::{broadcast, Mutex};
+use tokio::sync::broadcast;
 use openssl::ssl::{SslAcceptor, SslMethod, SslFiletype};
 
 mod config;
@@ -10,17 +9,38 @@ mod auth;
 mod handlers;
 mod build;
 mod websocket;
+mod sse;
+mod janitor;
+mod signed_url;
+mod validate;
 mod utils;
 
 use config::Config;
-use models::{AppState, BuildNextMessage, ServerMessage};
+use models::{AppState, ServerMessage};
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     
     env_logger::init();
+    // `--check-config` only validates config.toml and exits
+    let check_only = std::env::args().any(|arg| arg == "--check-config");
+
     // Load configuration
-    let config = Config::load("config.toml").expect("Failed to load config");
+    let config_path = "config.toml";
+    let config = match Config::load(config_path) {
+        Ok(config) => config,
+        Err(errors) => {
+            for error in &errors {
+                eprintln!("{}: {}", config_path, error);
+            }
+            eprintln!("{} has {} error(s)", config_path, errors.len());
+            std::process::exit(1);
+        }
+    };
+    if check_only {
+        println!("{} is valid", config_path);
+        return Ok(());
+    }
     let port = config.port;
     let ssl_enabled = config.ssl.enable_ssl;
     
@@ -28,17 +48,22 @@ async fn main() -> std::io::Result<()> {
     let cetificate_path = config.ssl.certificate_path.clone();
 
     let (project_sender,_b) = broadcast::channel::<ServerMessage>(100);
-    let (build_sender,_a) = broadcast::channel::<ServerMessage>(100);
-    let (queue_sender,_queue_receiver) = broadcast::channel::<BuildNextMessage>(100);
  
 
 
 
     
     // Create shared application state
-    let app_state = AppState::new(config,project_sender,build_sender,queue_sender).await;
+    let app_state = AppState::new(config,project_sender).await;
 
     let app_data = web::Data::new(app_state);
+    let shutdown_state = app_data.clone();
+
+    // Pick up the builds that were queued or running when the server last stopped
+    BuildManager::recover_builds(&app_data).await;
+
+    // Keep the log directories within each project's retention limits
+    janitor::start(app_data.clone());
 
     // let arc = Arc::new(app_data.clone());
    
@@ -57,7 +82,10 @@ async fn main() -> std::io::Result<()> {
         let mut app = App::new()
             .app_data(app_data.clone()) //need to see here
             // .wrap(Logger::default())
-            .service(handlers::health_check);
+            .service(handlers::health_check)
+            .service(websocket::server_observer_handler)
+            .service(websocket::protocol_schema)
+            .service(signed_url::signed_download_handler);
             // .service(build_handler);
             
         // Dynamically register project routes
@@ -65,7 +93,7 @@ async fn main() -> std::io::Result<()> {
         app
     });
     
-    if ssl_enabled {
+    let result = if ssl_enabled {
         let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls()).unwrap();
         builder.set_private_key_file(&certificate_key_path, SslFiletype::PEM).unwrap();
         builder.set_certificate_chain_file(&cetificate_path).unwrap();
@@ -73,5 +101,10 @@ async fn main() -> std::io::Result<()> {
         server.bind_openssl(format!("0.0.0.0:{}", port), builder)?.run().await
     } else {
         server.bind(("0.0.0.0", port))?.run().await
-    }
+    };
+
+    // Stop the running builds and kill what their commands started
+    BuildManager::abort_all_builds(&shutdown_state).await;
+
+    result
 }
\ No newline at end of file
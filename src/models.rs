@@ -1,12 +1,14 @@
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::path::PathBuf;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 use tokio::sync::{
-    Mutex, RwLock,
+    Mutex, RwLock, Semaphore,
     broadcast::{self, Sender},
+    watch,
 };
-use uuid::Uuid;
 
 use crate::config::Config;
 
@@ -15,12 +17,8 @@ pub struct AppState {
     pub config: Config,
     pub projects: Arc<RwLock<HashMap<String, ProjectState>>>,
     pub websocket_manager: Arc<WebSocketManager>,
-    pub project_sender: broadcast::Sender<ServerMessage>,
-    pub build_sender: broadcast::Sender<ServerMessage>,
-    pub queue_sender: broadcast::Sender<BuildNextMessage>,
-    pub is_queue_running: Arc<RwLock<bool>>,
-    pub running_command_child: Arc<Mutex<Option<tokio::process::Child>>>,
-    pub is_terminated: Arc<Mutex<bool>>,
+    pub project_sender: broadcast::Sender<ServerMessage>, // events of every project, for the server observer socket
+    pub build_slots: Arc<Semaphore>,
 }
 
 #[derive(Clone)]
@@ -28,15 +26,19 @@ pub struct ProjectState {
     pub build_queue: Arc<Mutex<Vec<BuildRequest>>>,
     pub current_build: Arc<Mutex<Option<BuildProcess>>>,
     pub build_history: Arc<Mutex<Vec<BuildResult>>>,
+    pub is_queue_running: Arc<RwLock<bool>>,
+    pub event_sender: broadcast::Sender<ServerMessage>, // events of this project, for its observer socket
+    pub persist_lock: Arc<Mutex<()>>,                   // keeps writes of the queue file in order
+    pub workspace_lock: Arc<Mutex<()>>, // held by a cleanup while it runs, no build starts meanwhile
 }
 
-#[derive(Clone)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct BuildRequest {
     pub id: String,
     pub project_name: String,
     pub unique_id: String,
     pub payload: HashMap<String, serde_json::Value>,
-    pub files: HashMap<String, String>,
+    pub files: HashMap<String, PathBuf>, // file name -> upload in the spool directory
     pub created_at: DateTime<Utc>,
     pub socket_token: String,
 }
@@ -52,7 +54,14 @@ pub struct BuildProcess {
     pub started_at: DateTime<Utc>,
     pub socket_token: String,
     pub logs: Vec<BuildLog>,
-    pub handle: Option<tokio::task::JoinHandle<()>>,
+    pub handle: Option<tokio::task::AbortHandle>, // build task, aborted on shutdown
+    pub child_pid: Option<u32>,
+    pub cancel_token: CancellationToken,
+    pub send_to_sock: bool,
+    pub steps: Vec<StepResult>,
+    pub variables: BuildVariables,
+    pub request: BuildRequest,
+    pub artifacts: Vec<Artifact>,
 }
 
 impl Clone for BuildProcess {
@@ -67,11 +76,31 @@ impl Clone for BuildProcess {
             started_at: self.started_at,
             socket_token: self.socket_token.clone(),
             logs: self.logs.clone(),
-            handle: None, // Clone skips the task handle
+            handle: self.handle.clone(),
+            child_pid: self.child_pid,
+            cancel_token: self.cancel_token.clone(),
+            send_to_sock: self.send_to_sock,
+            steps: self.steps.clone(),
+            variables: self.variables.clone(),
+            request: self.request.clone(),
+            artifacts: self.artifacts.clone(),
         }
     }
 }
 
+/// The queue and running build of a project as written to disk, so a restart can recover them
+#[derive(Default, Serialize, Deserialize)]
+pub struct PersistedQueue {
+    pub running: Option<RunningBuild>,
+    pub queue: Vec<BuildRequest>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RunningBuild {
+    pub request: BuildRequest,
+    pub started_at: DateTime<Utc>,
+}
+
 // #[derive(Clone)]
 // pub struct BuildProcess {
 //     pub id: String,
@@ -85,6 +114,53 @@ impl Clone for BuildProcess {
 //     pub handle: Option<tokio::task::JoinHandle<()>>,
 // }
 
+/// Cancellation flag of a single build, shared by the build task and whoever aborts it
+#[derive(Clone)]
+pub struct CancellationToken {
+    sender: Arc<watch::Sender<bool>>,
+    timed_out: Arc<AtomicBool>,
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        let (sender, _) = watch::channel(false);
+        Self {
+            sender: Arc::new(sender),
+            timed_out: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    pub fn cancel(&self) {
+        self.sender.send_replace(true);
+    }
+
+    /// Cancels the build because it ran longer than `max_build_seconds`
+    pub fn time_out(&self) {
+        self.timed_out.store(true, Ordering::SeqCst);
+        self.cancel();
+    }
+
+    pub fn is_timed_out(&self) -> bool {
+        self.timed_out.load(Ordering::SeqCst)
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        *self.sender.borrow()
+    }
+
+    /// Resolves once the build is cancelled
+    pub async fn cancelled(&self) {
+        let mut receiver = self.sender.subscribe();
+        let _ = receiver.wait_for(|cancelled| *cancelled).await;
+    }
+}
+
+impl Default for CancellationToken {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum BuildStatus {
     Queued,
@@ -92,18 +168,24 @@ pub enum BuildStatus {
     Success,
     Failed,
     Aborted,
+    TimedOut,
+    Interrupted, // the server stopped while it was running
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct BuildLog {
+    #[serde(default)]
+    pub seq: u64, // 1 based, increases with every log of the build
     pub timestamp: DateTime<Utc>,
     pub step: usize,
     pub level: LogLevel,
     pub message: String,
     pub command: Option<String>,
+    #[serde(default)]
+    pub hidden: bool, // logged by a step with send_to_sock = false, never shown on a build socket
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub enum LogLevel {
     Info,
     Warning,
@@ -111,32 +193,265 @@ pub enum LogLevel {
     Success,
 }
 
+/// Variables of a running build, built from the payload by `utils::build_variables`
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct BuildVariables {
+    /// `$name` and `$$name` payload fields, exported to every command
+    pub env: HashMap<String, String>,
+    /// Everything `${name}` can refer to: payload fields, build info and values set while building
+    pub values: HashMap<String, String>,
+    /// Values of `$$name` fields, masked in logs
+    pub secrets: Vec<String>,
+    /// Values captured from the output of steps with `capture`
+    pub captured: HashMap<String, String>,
+}
+
+impl BuildVariables {
+    pub fn mask(&self, text: &str) -> String {
+        self.secrets
+            .iter()
+            .filter(|secret| !secret.is_empty())
+            .fold(text.to_string(), |text, secret| {
+                text.replace(secret.as_str(), "***")
+            })
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum StepOutcome {
+    Success,
+    Failed,
+    Ignored,
+    Aborted,
+    TimedOut,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct StepResult {
+    pub step: usize,
+    pub title: String,
+    pub outcome: StepOutcome,
+    pub attempts: u32,
+    pub error: Option<String>,
+}
+
+/// What a cleanup request ran and removed, returned by the cleanup endpoint
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct CleanupReport {
+    pub success: bool,
+    pub commands: Vec<CleanupCommandResult>,
+    pub removed: Vec<String>,
+    pub error: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct CleanupCommandResult {
+    pub name: String,
+    pub success: bool,
+    pub exit_code: Option<i32>,
+    pub stdout: String,
+    pub stderr: String,
+    pub error: Option<String>,
+}
+
 #[derive(Clone)]
 pub enum ServerMessage {
     Data(String),
+    Message(SocketMessage),
     Shutdown,
 }
 
-#[derive(Clone)]
-pub enum BuildNextMessage {
-    Project(String),
-    Shutdown,
+/// Version of the websocket protocol, sent as `v` in every message
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Messages sent on the build and observer sockets, serialized as
+/// `{"v": 1, "type": "<snake_case variant>", ...fields}`
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SocketMessage {
+    Hello {
+        build_id: String,
+        state: String, // "queued", "running", "finished"
+    },
+    Snapshot {
+        build_id: String,
+        status: BuildStatus,
+        current_step: usize,
+        total_steps: usize,
+        logs: Vec<BuildLog>,
+    },
+    Log {
+        build_id: String,
+        #[serde(flatten)]
+        log: BuildLog,
+    },
+    StepStarted {
+        build_id: String,
+        step: usize,
+        title: String,
+        total_steps: usize,
+    },
+    StepFinished {
+        build_id: String,
+        #[serde(flatten)]
+        result: StepResult,
+    },
+    Status {
+        build_id: String,
+        status: BuildStatus,
+    },
+    QueuePosition {
+        build_id: String,
+        position: usize,
+        queue_length: usize,
+    },
+    BuildStarted {
+        build_id: String,
+        unique_id: String,
+        total_steps: usize,
+    },
+    QueueChanged {
+        queue_length: usize,
+        queue: Vec<QueueEntry>,
+    },
+    BuildFinished {
+        build_id: String,
+        unique_id: String,
+        status: BuildStatus,
+        duration_seconds: u64,
+    },
+    Ack {
+        command: String,
+    },
+    Pong {
+        id: Option<u64>,
+    },
+    Error {
+        message: String,
+    },
+}
+
+/// Commands a client sends on its build socket, `{"type": "abort"}` and the like
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientMessage {
+    Abort,
+    Subscribe {
+        #[serde(default)]
+        steps: Option<Vec<usize>>, // None = every step
+        #[serde(default)]
+        levels: Option<Vec<LogLevel>>, // None = every level
+    },
+    Ping {
+        #[serde(default)]
+        id: Option<u64>,
+    },
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct QueueEntry {
+    pub build_id: String,
+    pub unique_id: String,
+    pub position: usize,
+}
+
+#[derive(Serialize)]
+struct SocketEnvelope<'a> {
+    v: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    project: Option<&'a str>,
+    #[serde(flatten)]
+    message: &'a SocketMessage,
+}
+
+impl SocketMessage {
+    /// Serializes the message, observer sockets also get the project it belongs to
+    pub fn to_json(&self, project: Option<&str>) -> String {
+        serde_json::to_string(&SocketEnvelope {
+            v: PROTOCOL_VERSION,
+            project,
+            message: self,
+        })
+        .unwrap()
+    }
+
+    /// Fields of every message type, served so clients can check what they parse against
+    pub fn schema() -> serde_json::Value {
+        let log = serde_json::json!({
+            "seq": "u64", "timestamp": "rfc3339", "step": "usize",
+            "level": "Info|Warning|Error|Success", "message": "string", "command": "string|null"
+        });
+        serde_json::json!({
+            "version": PROTOCOL_VERSION,
+            "envelope": { "v": "u32", "type": "string", "project": "string, observer sockets only" },
+            "statuses": ["Queued", "Running", "Success", "Failed", "Aborted", "TimedOut", "Interrupted"],
+            "messages": {
+                "hello": { "build_id": "string", "state": "queued|running|finished" },
+                "snapshot": {
+                    "build_id": "string", "status": "status", "current_step": "usize",
+                    "total_steps": "usize", "logs": [log]
+                },
+                "log": { "build_id": "string", "...": log },
+                "step_started": {
+                    "build_id": "string", "step": "usize", "title": "string", "total_steps": "usize"
+                },
+                "step_finished": {
+                    "build_id": "string", "step": "usize", "title": "string",
+                    "outcome": "Success|Failed|Ignored|Aborted|TimedOut", "attempts": "u32",
+                    "error": "string|null"
+                },
+                "status": { "build_id": "string", "status": "status" },
+                "queue_position": { "build_id": "string", "position": "usize", "queue_length": "usize" },
+                "build_started": { "build_id": "string", "unique_id": "string", "total_steps": "usize" },
+                "queue_changed": {
+                    "queue_length": "usize",
+                    "queue": [{ "build_id": "string", "unique_id": "string", "position": "usize" }]
+                },
+                "build_finished": {
+                    "build_id": "string", "unique_id": "string", "status": "status",
+                    "duration_seconds": "u64"
+                },
+                "ack": { "command": "abort|subscribe" },
+                "pong": { "id": "u64|null" },
+                "error": { "message": "string" }
+            },
+            "client_messages": {
+                "abort": {},
+                "subscribe": { "steps": "[usize]|null", "levels": "[Info|Warning|Error|Success]|null" },
+                "ping": { "id": "u64|null" }
+            }
+        })
+    }
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct BuildResult {
     pub id: String,
     pub project_name: String,
+    pub unique_id: String,
+    pub socket_token: String,
     pub status: BuildStatus,
     pub started_at: DateTime<Utc>,
     pub completed_at: DateTime<Utc>,
     pub logs: Vec<BuildLog>,
+    pub steps: Vec<StepResult>,
+    pub captured: HashMap<String, String>,
     pub duration_seconds: u64,
+    #[serde(default)]
+    pub artifacts: Vec<Artifact>,
+}
+
+/// A file a successful build produced, copied to `<log_path>/artifacts/<build_id>/<name>`
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Artifact {
+    pub name: String, // path relative to the project, as matched by the `artifacts` globs
+    pub size: u64,
+    pub sha256: String,
 }
 
 #[derive(Clone)]
 pub struct WebSocketManager {
-    pub connections: Arc<Mutex<HashMap<String, Vec<broadcast::Sender<String>>>>>,
+    pub connections: Arc<Mutex<HashMap<String, broadcast::Sender<ServerMessage>>>>,
 }
 
 // API Request/Response models
@@ -159,6 +474,8 @@ pub struct BuildStatusResponse {
     pub is_building: bool,
     pub queue_length: usize,
     pub current_build: Option<BuildInfo>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub data: Option<serde_json::Value>,
 }
 
 #[derive(Serialize)]
@@ -170,39 +487,87 @@ pub struct BuildInfo {
     pub socket_token: String,
 }
 
+/// Body of the sign endpoint, `artifact` or `log` picks the file the link is for
+#[derive(Deserialize)]
+pub struct SignRequest {
+    pub build_id: String,
+    #[serde(default)]
+    pub artifact: Option<String>,
+    #[serde(default)]
+    pub log: bool,
+    #[serde(default)]
+    pub expires_in: Option<u64>, // seconds, the configured default_ttl when missing
+}
+
+/// Expiry and signature of a signed download link
+#[derive(Deserialize)]
+pub struct SignedUrlQuery {
+    pub expires: i64, // unix timestamp
+    pub signature: String,
+}
+
+/// Filters and page of the build history endpoint
+#[derive(Deserialize)]
+pub struct HistoryQuery {
+    pub status: Option<String>,
+    pub unique_id: Option<String>,
+    pub from: Option<DateTime<Utc>>, // started at or after
+    pub to: Option<DateTime<Utc>>,   // started before
+    #[serde(default = "default_page")]
+    pub page: usize,
+    #[serde(default = "default_per_page")]
+    pub per_page: usize,
+}
+
+fn default_page() -> usize {
+    1
+}
+
+fn default_per_page() -> usize {
+    20
+}
+
 #[derive(Deserialize, Serialize, Clone)]
 pub struct WebSocketQuery {
     pub token: String,
+    #[serde(default)]
+    pub since: u64, // replay only the logs after this sequence number
 }
 
 impl AppState {
-    pub async fn new(
-        config: Config,
-        project_sender: Sender<ServerMessage>,
-        build_sender: Sender<ServerMessage>,
-        queue_sender: Sender<BuildNextMessage>,
-    ) -> Self {
+    pub async fn new(config: Config, project_sender: Sender<ServerMessage>) -> Self {
         let mut projects = HashMap::new();
 
-        for (name, _) in &config.projects {
+        for name in config.projects.keys() {
+            let history = crate::utils::load_history(&config.log_path, name).unwrap_or_else(|e| {
+                log::error!("Failed to load build history of {}: {}", name, e);
+                Vec::new()
+            });
+
             projects.insert(
                 name.clone(),
                 ProjectState {
                     build_queue: Arc::new(Mutex::new(Vec::new())),
                     current_build: Arc::new(Mutex::new(None)),
-                    build_history: Arc::new(Mutex::new(Vec::new())),
+                    build_history: Arc::new(Mutex::new(history)),
+                    is_queue_running: Arc::new(RwLock::new(false)),
+                    event_sender: broadcast::channel(100).0,
+                    persist_lock: Arc::new(Mutex::new(())),
+                    workspace_lock: Arc::new(Mutex::new(())),
                 },
             );
         }
 
+        // 0 means no limit on how many projects may build at the same time
+        let build_slots = match config.max_parallel_projects {
+            0 => Semaphore::MAX_PERMITS,
+            limit => limit,
+        };
+
         Self {
             config,
-            is_terminated: Arc::new(Mutex::new(false)),
-            running_command_child: Arc::new(Mutex::new(None)),
             project_sender,
-            build_sender,
-            queue_sender,
-            is_queue_running: Arc::new(RwLock::new(false)),
+            build_slots: Arc::new(Semaphore::new(build_slots)),
 
             projects: Arc::new(RwLock::new(projects)),
             websocket_manager: Arc::new(WebSocketManager {
@@ -212,27 +577,49 @@ impl AppState {
     }
 }
 
+impl AppState {
+    /// Sends an event to the observers of the project and of the whole server
+    pub fn publish_event(
+        &self,
+        project_state: &ProjectState,
+        project_name: &str,
+        event: &SocketMessage,
+    ) {
+        let message = event.to_json(Some(project_name));
+        let _ = project_state
+            .event_sender
+            .send(ServerMessage::Data(message.clone()));
+        let _ = self.project_sender.send(ServerMessage::Data(message));
+    }
+}
+
 impl WebSocketManager {
-    // pub async fn add_connection(&self, token: &str,sender:Sender<String>) -> broadcast::Receiver<String> {
-    //     let mut connections = self.connections.lock().await;
-    //     let mut list = connections.get(token).unwrap_or(&Vec::new()).clone();
-    //     let new_receiver = sender.subscribe();
-
-    //     list.push(sender);
-    //     connections.insert(token.to_string(), list);
-
-    //     new_receiver
-    // }
-
-    // pub async fn send_message(&self, token: &str, message: &str) {
-    //     let connections = self.connections.lock().await;
-    //     if let Some(sender) = connections.get(token) {
-    //         let _ = sender.send(message.to_string());
-    //     }
-    // }
-
-    // pub async fn remove_connection(&self, token: &str) {
-    //     let mut connections = self.connections.lock().await;
-    //     connections.remove(token);
-    // }
+    /// Opens the channel a build streams its logs and status on, keyed by its socket token
+    pub async fn open_channel(&self, token: &str) {
+        let mut connections = self.connections.lock().await;
+        connections
+            .entry(token.to_string())
+            .or_insert_with(|| broadcast::channel(100).0);
+    }
+
+    /// Listens on the build's channel, None when no build has it open
+    pub async fn subscribe(&self, token: &str) -> Option<broadcast::Receiver<ServerMessage>> {
+        let connections = self.connections.lock().await;
+        connections.get(token).map(|sender| sender.subscribe())
+    }
+
+    pub async fn send_message(&self, token: &str, message: &SocketMessage) {
+        let connections = self.connections.lock().await;
+        if let Some(sender) = connections.get(token) {
+            let _ = sender.send(ServerMessage::Message(message.clone()));
+        }
+    }
+
+    /// Closes the sockets of the build and forgets its channel
+    pub async fn close_channel(&self, token: &str) {
+        let mut connections = self.connections.lock().await;
+        if let Some(sender) = connections.remove(token) {
+            let _ = sender.send(ServerMessage::Shutdown);
+        }
+    }
 }
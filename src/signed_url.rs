@@ -0,0 +1,313 @@
+use actix_web::{HttpResponse, Result, get, web};
+use chrono::Utc;
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
+
+use crate::handlers::{file_response, finished_build, payload_error};
+use crate::models::{AppState, SignedUrlQuery};
+use crate::utils;
+
+/// File part of a signed link to a build log
+pub const LOG_FILE: &str = "log";
+/// Prefix of the file part of a signed link to an artifact, followed by the artifact name
+pub const ARTIFACT_PREFIX: &str = "artifacts/";
+
+/// Hex HMAC-SHA256 of a link, covering its project, build, file and expiry
+pub fn sign(
+    secret: &str,
+    project_name: &str,
+    build_id: &str,
+    file: &str,
+    expires: i64,
+) -> Result<String, String> {
+    let key = PKey::hmac(secret.as_bytes()).map_err(|e| e.to_string())?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &key).map_err(|e| e.to_string())?;
+    let message = format!("{}\n{}\n{}\n{}", project_name, build_id, file, expires);
+    let signature = signer
+        .sign_oneshot_to_vec(message.as_bytes())
+        .map_err(|e| e.to_string())?;
+
+    Ok(signature
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect())
+}
+
+/// Path and query of a signed link, served by `signed_download_handler`
+pub fn signed_path(
+    project_name: &str,
+    build_id: &str,
+    file: &str,
+    expires: i64,
+    signature: &str,
+) -> String {
+    format!(
+        "/download/{}/{}/{}?expires={}&signature={}",
+        utils::url_encode(project_name, false),
+        utils::url_encode(build_id, false),
+        utils::url_encode(file, true),
+        expires,
+        signature
+    )
+}
+
+/// What a signed link points to and until when
+struct Link<'a> {
+    project_name: &'a str,
+    build_id: &'a str,
+    file: &'a str,
+    expires: i64,
+}
+
+#[derive(Debug, PartialEq)]
+enum LinkError {
+    InvalidSignature,
+    Expired,
+}
+
+impl Link<'_> {
+    /// Checks the signature first, so an expiry that was changed is refused as invalid
+    fn verify(&self, secret: &str, signature: &str, now: i64) -> Result<(), LinkError> {
+        // Compared in constant time, so the signature cannot be guessed byte by byte
+        let valid = sign(
+            secret,
+            self.project_name,
+            self.build_id,
+            self.file,
+            self.expires,
+        )
+        .is_ok_and(|expected| {
+            expected.len() == signature.len()
+                && openssl::memcmp::eq(expected.as_bytes(), signature.as_bytes())
+        });
+        if !valid {
+            return Err(LinkError::InvalidSignature);
+        }
+        if self.expires < now {
+            return Err(LinkError::Expired);
+        }
+        Ok(())
+    }
+}
+
+/// Serves the artifact or log of a signed link, the signature stands in for the project auth
+#[get("/download/{project}/{build_id}/{file:.*}")]
+pub async fn signed_download_handler(
+    path: web::Path<(String, String, String)>,
+    query: web::Query<SignedUrlQuery>,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let (project_name, build_id, file) = path.into_inner();
+    let Some(signed_urls) = &state.config.signed_urls else {
+        return Ok(payload_error(
+            HttpResponse::NotFound(),
+            "not_found",
+            "Signed links are not enabled".to_string(),
+        ));
+    };
+
+    let link = Link {
+        project_name: &project_name,
+        build_id: &build_id,
+        file: &file,
+        expires: query.expires,
+    };
+    match link.verify(
+        &signed_urls.secret,
+        &query.signature,
+        Utc::now().timestamp(),
+    ) {
+        Ok(()) => {}
+        Err(LinkError::InvalidSignature) => {
+            return Ok(payload_error(
+                HttpResponse::Forbidden(),
+                "invalid_signature",
+                "Invalid signature".to_string(),
+            ));
+        }
+        Err(LinkError::Expired) => {
+            return Ok(payload_error(
+                HttpResponse::Gone(),
+                "expired",
+                "Link expired".to_string(),
+            ));
+        }
+    }
+
+    let Some(result) = finished_build(&state, &project_name, &build_id).await else {
+        return Ok(payload_error(
+            HttpResponse::NotFound(),
+            "not_found",
+            format!("Build not found: {}", build_id),
+        ));
+    };
+    let log_path = state.config.project_log_path(&project_name);
+
+    if file == LOG_FILE {
+        // The janitor may have compressed it since the link was made
+        let compressed = utils::compressed_log_file(log_path, &build_id);
+        let (path, name) = if compressed.exists() {
+            (compressed, format!("{}.log.gz", build_id))
+        } else {
+            (
+                utils::build_log_file(log_path, &build_id),
+                format!("{}.log", build_id),
+            )
+        };
+        return Ok(file_response(&path, &name).await);
+    }
+
+    let artifact = file.strip_prefix(ARTIFACT_PREFIX).and_then(|name| {
+        result
+            .artifacts
+            .iter()
+            .find(|artifact| artifact.name == name)
+    });
+    let Some(artifact) = artifact else {
+        return Ok(payload_error(
+            HttpResponse::NotFound(),
+            "not_found",
+            format!("File not found: {}", file),
+        ));
+    };
+
+    let path = utils::artifact_dir(log_path, &build_id).join(&artifact.name);
+    Ok(file_response(&path, &artifact.name).await)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use actix_web::App;
+    use actix_web::http::StatusCode;
+    use actix_web::test::{TestRequest, call_service, init_service};
+    use tokio::sync::broadcast;
+
+    const SECRET: &str = "0123456789abcdef";
+    const NOW: i64 = 1_700_000_000;
+
+    fn link<'a>(project_name: &'a str, build_id: &'a str, file: &'a str, expires: i64) -> Link<'a> {
+        Link {
+            project_name,
+            build_id,
+            file,
+            expires,
+        }
+    }
+
+    fn signature(expires: i64) -> String {
+        sign(SECRET, "app", "build-1", "artifacts/app.apk", expires).unwrap()
+    }
+
+    #[test]
+    fn signs_as_hex_sha256() {
+        let signature = signature(NOW);
+
+        assert_eq!(signature.len(), 64);
+        assert!(signature.chars().all(|c| c.is_ascii_hexdigit()));
+        assert_eq!(signature, self::signature(NOW));
+    }
+
+    #[test]
+    fn accepts_a_valid_link() {
+        let expires = NOW + 60;
+        let link = link("app", "build-1", "artifacts/app.apk", expires);
+
+        assert_eq!(link.verify(SECRET, &signature(expires), NOW), Ok(()));
+    }
+
+    #[test]
+    fn refuses_a_tampered_link() {
+        let expires = NOW + 60;
+        let signature = signature(expires);
+        let tampered = [
+            link("other", "build-1", "artifacts/app.apk", expires),
+            link("app", "build-2", "artifacts/app.apk", expires),
+            link("app", "build-1", "artifacts/other.apk", expires),
+            link("app", "build-1", LOG_FILE, expires),
+            link("app", "build-1", "artifacts/app.apk", expires + 3600),
+        ];
+
+        for link in tampered {
+            assert_eq!(
+                link.verify(SECRET, &signature, NOW),
+                Err(LinkError::InvalidSignature)
+            );
+        }
+    }
+
+    #[test]
+    fn refuses_another_secret_or_a_cut_signature() {
+        let expires = NOW + 60;
+        let link = link("app", "build-1", "artifacts/app.apk", expires);
+        let signature = signature(expires);
+
+        assert_eq!(
+            link.verify("another-secret-key", &signature, NOW),
+            Err(LinkError::InvalidSignature)
+        );
+        assert_eq!(
+            link.verify(SECRET, &signature[..32], NOW),
+            Err(LinkError::InvalidSignature)
+        );
+    }
+
+    #[test]
+    fn refuses_an_expired_link() {
+        let expires = NOW - 1;
+        let link = link("app", "build-1", "artifacts/app.apk", expires);
+
+        assert_eq!(
+            link.verify(SECRET, &signature(expires), NOW),
+            Err(LinkError::Expired)
+        );
+    }
+
+    #[test]
+    fn encodes_the_path_but_keeps_the_artifact_folders() {
+        assert_eq!(
+            signed_path("my app", "build-1", "artifacts/out dir/a&b.apk", 5, "ab"),
+            "/download/my%20app/build-1/artifacts/out%20dir/a%26b.apk?expires=5&signature=ab"
+        );
+    }
+
+    #[actix_web::test]
+    async fn download_answers_403_and_410() {
+        let config: Config = toml::from_str(&format!(
+            r#"
+            name = "test"
+            port = 8080
+            base_path = ""
+            log_path = "target/signed_url_test_logs"
+            projects = {{}}
+            ssl = {{ enable_ssl = false, certificate_path = "", certificate_key_path = "" }}
+            auth = {{ auth_type = "token", address_type = "ip", allowed_addresses = [], allowed_tokens = ["t"] }}
+            signed_urls = {{ secret = "{}" }}
+            "#,
+            SECRET
+        ))
+        .unwrap();
+        let state = web::Data::new(AppState::new(config, broadcast::channel(1).0).await);
+        let app = init_service(App::new().app_data(state).service(signed_download_handler)).await;
+
+        let expires = Utc::now().timestamp() - 60;
+        let signature = sign(SECRET, "app", "build-1", LOG_FILE, expires).unwrap();
+        let requests = [
+            (
+                signed_path("app", "build-1", LOG_FILE, expires, &signature),
+                StatusCode::GONE,
+            ),
+            (
+                signed_path("app", "build-2", LOG_FILE, expires, &signature),
+                StatusCode::FORBIDDEN,
+            ),
+        ];
+
+        for (path, status) in requests {
+            let request = TestRequest::get().uri(&path).to_request();
+            assert_eq!(call_service(&app, request).await.status(), status);
+        }
+    }
+}
@@ -0,0 +1,84 @@
+use actix_web::web::Bytes;
+use actix_web::{Error, HttpRequest, HttpResponse, web};
+use futures_util::stream;
+use serde_json::json;
+use std::collections::VecDeque;
+use std::time::Duration;
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use crate::handlers::extract_project_name;
+use crate::models::{AppState, ServerMessage, SocketMessage, WebSocketQuery};
+use crate::websocket::{BuildStream, open_build_stream};
+
+/// How often an idle event stream gets a comment line, so proxies keep it open
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+type EventState = (
+    VecDeque<SocketMessage>,
+    Option<broadcast::Receiver<ServerMessage>>,
+);
+
+/// Server-Sent Events version of the build socket, with the same token rules and messages.
+/// `Last-Event-ID` resumes after that log seq like `since` does
+pub async fn sse_handler(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    query: web::Query<WebSocketQuery>,
+) -> Result<HttpResponse, Error> {
+    let project_name = extract_project_name(&req, &state.config)?;
+
+    // A reconnecting EventSource sends the id of the last event it got
+    let since = req
+        .headers()
+        .get("Last-Event-ID")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse().ok())
+        .unwrap_or(query.since);
+
+    let Some(build_stream) = open_build_stream(&state, &project_name, &query.token, since).await
+    else {
+        return Ok(HttpResponse::BadRequest().json(json!({
+            "error": "Invalid or expired token"
+        })));
+    };
+
+    let initial: EventState = match build_stream {
+        BuildStream::Live { greeting, receiver } => (greeting.into(), Some(receiver)),
+        BuildStream::Finished { replay } => (replay.into(), None),
+    };
+
+    let events = stream::unfold(initial, |(mut pending, mut receiver)| async move {
+        if let Some(message) = pending.pop_front() {
+            return Some((Ok::<_, Error>(sse_event(&message)), (pending, receiver)));
+        }
+
+        // A finished build ends the stream after its replay
+        let next = match tokio::time::timeout(KEEPALIVE_INTERVAL, receiver.as_mut()?.recv()).await {
+            Err(_) => Bytes::from_static(b": keepalive\n\n"),
+            Ok(Ok(ServerMessage::Message(message))) => sse_event(&message),
+            Ok(Ok(ServerMessage::Data(data))) => Bytes::from(format!("data: {}\n\n", data)),
+            Ok(Ok(ServerMessage::Shutdown)) | Ok(Err(RecvError::Closed)) => return None,
+            Ok(Err(RecvError::Lagged(skipped))) => sse_event(&SocketMessage::Error {
+                message: format!("Missed {} messages, reconnect with Last-Event-ID", skipped),
+            }),
+        };
+        Some((Ok(next), (pending, receiver)))
+    });
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"))
+        .streaming(events))
+}
+
+/// One event, log carrying messages get the seq of their last log as id
+fn sse_event(message: &SocketMessage) -> Bytes {
+    let seq = match message {
+        SocketMessage::Log { log, .. } => Some(log.seq),
+        SocketMessage::Snapshot { logs, .. } => logs.last().map(|log| log.seq),
+        _ => None,
+    };
+    let id = seq.map(|seq| format!("id: {}\n", seq)).unwrap_or_default();
+
+    Bytes::from(format!("{}data: {}\n\n", id, message.to_json(None)))
+}
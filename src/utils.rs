@@ -1,18 +1,24 @@
 use chrono::Utc;
 use rand::{Rng, distributions::Alphanumeric};
+use regex::Regex;
 use reqwest::Client;
 use serde_json::{Value, json};
 use std::collections::HashMap;
 use std::env;
 use std::fs;
-use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
 
-use std::io::Write;
-use std::path::Path;
+use flate2::read::GzDecoder;
+use std::io::Read;
+use std::path::{Component, Path, PathBuf};
+use std::time::Duration;
 
 use crate::build::BuildManager;
-use crate::models::AppState;
-use crate::models::BuildResult;
+use crate::config::{CommandConfig, ReturnField};
+use crate::models::{
+    AppState, Artifact, BuildLog, BuildRequest, BuildResult, BuildVariables, LogLevel,
+    PersistedQueue, ProjectState,
+};
 
 pub fn generate_token(length: usize) -> String {
     rand::thread_rng()
@@ -24,93 +30,930 @@ pub fn generate_token(length: usize) -> String {
 pub async fn read_output_lines(
     stream: Option<impl tokio::io::AsyncRead + Unpin>,
     step: usize,
-    status: &str,
+    level: LogLevel,
     state: &actix_web::web::Data<AppState>,
+    project_state: &ProjectState,
+    build_id: &str,
+    mut capture: Option<&mut OutputCapture>,
 ) {
     if let Some(output) = stream {
         let reader = BufReader::new(output);
         let mut lines = reader.lines();
 
         while let Ok(Some(line)) = lines.next_line().await {
-            //send_output(state, step, status, &line).await;
+            if let Some(capture) = capture.as_mut() {
+                capture.feed(&line);
+            }
             BuildManager::send_log(
                 state,
                 project_state,
                 build_id,
                 step,
-                level,
-                message,
-                command,
+                level.clone(),
+                line,
+                None,
             )
+            .await;
         }
     }
 }
 
-pub fn resolve_variable(
-    variable: &str,
-    payload: &HashMap<String, Value>,
-    socket_token: &str,
-) -> String {
+/// Prefix of the stdout lines read by `capture_mode = "marker"`, e.g. `##capture apk_path=app.apk`
+pub const CAPTURE_MARKER: &str = "##capture ";
+
+enum CaptureMode {
+    LastLine,
+    Regex(Regex),
+    Marker,
+}
+
+/// Collects the value a step declares with `capture` while its stdout is read
+pub struct OutputCapture {
+    name: String,
+    mode: CaptureMode,
+    last: Option<String>,
+    markers: Vec<(String, String)>,
+}
+
+impl OutputCapture {
+    pub fn new(command_config: &CommandConfig) -> Result<Option<Self>, String> {
+        let Some(name) = &command_config.capture else {
+            return Ok(None);
+        };
+
+        let mode = match command_config.capture_mode.as_str() {
+            "" | "last_line" => CaptureMode::LastLine,
+            "marker" => CaptureMode::Marker,
+            "regex" => {
+                let pattern = command_config
+                    .capture_regex
+                    .as_deref()
+                    .ok_or("capture_mode \"regex\" needs a capture_regex")?;
+                let regex = Regex::new(pattern)
+                    .map_err(|e| format!("Invalid capture_regex {}: {}", pattern, e))?;
+                CaptureMode::Regex(regex)
+            }
+            other => return Err(format!("Unknown capture_mode: {}", other)),
+        };
+
+        Ok(Some(Self {
+            name: name.clone(),
+            mode,
+            last: None,
+            markers: Vec::new(),
+        }))
+    }
+
+    pub fn feed(&mut self, line: &str) {
+        match &self.mode {
+            CaptureMode::LastLine => {
+                if !line.trim().is_empty() {
+                    self.last = Some(line.trim().to_string());
+                }
+            }
+            // The first group when the regex has one, otherwise the whole match
+            CaptureMode::Regex(regex) => {
+                if let Some(captures) = regex.captures(line)
+                    && let Some(value) = captures.get(1).or_else(|| captures.get(0))
+                {
+                    self.last = Some(value.as_str().to_string());
+                }
+            }
+            CaptureMode::Marker => {
+                if let Some(marker) = line.trim_start().strip_prefix(CAPTURE_MARKER)
+                    && let Some((key, value)) = marker.split_once('=')
+                    && (self.name == "*" || self.name == key.trim())
+                {
+                    self.markers
+                        .push((key.trim().to_string(), value.trim().to_string()));
+                }
+            }
+        }
+    }
+
+    /// The captured `(name, value)` pairs, later markers overwrite earlier ones
+    pub fn finish(self) -> Vec<(String, String)> {
+        match self.mode {
+            CaptureMode::Marker => self.markers,
+            _ => self
+                .last
+                .map(|value| vec![(self.name, value)])
+                .unwrap_or_default(),
+        }
+    }
+}
+
+/// Working directory of a project, `project_path` is taken as is when `base_path` is empty
+/// or when it is already absolute
+pub fn resolve_project_path(base_path: &str, project_path: &str) -> PathBuf {
+    Path::new(base_path).join(project_path)
+}
+
+/// Where an uploaded file is written inside the project workspace, absolute paths and
+/// `..` are rejected so a file cannot land outside of it, and `.` as it is the workspace itself
+pub fn resolve_upload_path(workdir: &Path, path: &str) -> Result<PathBuf, String> {
+    let relative = Path::new(path);
+    let inside = relative
+        .components()
+        .all(|component| matches!(component, Component::Normal(_) | Component::CurDir));
+    let names_an_entry = relative
+        .components()
+        .any(|component| matches!(component, Component::Normal(_)));
+    if !inside || !names_an_entry || path.trim().is_empty() {
+        return Err(format!("File path must stay inside the project: {}", path));
+    }
+    Ok(workdir.join(relative))
+}
+
+/// Splits a command into its arguments for running it without a shell,
+/// single and double quotes group words and a backslash escapes the next character
+pub fn split_command(command: &str) -> Result<Vec<String>, String> {
+    let mut args = Vec::new();
+    let mut current = String::new();
+    let mut in_word = false;
+    let mut quote: Option<char> = None;
+    let mut chars = command.chars();
+
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some('"'), '\\') | (None, '\\') => match chars.next() {
+                Some(escaped) => {
+                    current.push(escaped);
+                    in_word = true;
+                }
+                None => return Err("Command ends with a backslash".to_string()),
+            },
+            (Some(_), c) => current.push(c),
+            (None, '\'' | '"') => {
+                quote = Some(c);
+                in_word = true;
+            }
+            (None, c) if c.is_whitespace() => {
+                if in_word {
+                    args.push(std::mem::take(&mut current));
+                    in_word = false;
+                }
+            }
+            (None, c) => {
+                current.push(c);
+                in_word = true;
+            }
+        }
+    }
+
+    if quote.is_some() {
+        return Err("Command has an unclosed quote".to_string());
+    }
+    if in_word {
+        args.push(current);
+    }
+
+    Ok(args)
+}
+
+/// Values the `%name%` placeholders of an endpoint's `return` fields resolve to
+pub struct ResponseContext<'a> {
+    pub status: &'a str,
+    pub project_name: &'a str,
+    pub unique_id: Option<&'a str>,
+    pub socket_token: Option<&'a str>,
+    pub build_id: Option<&'a str>,
+    pub queue_position: Option<usize>,
+    pub payload: &'a HashMap<String, Value>,
+}
+
+pub fn resolve_variable(variable: &str, context: &ResponseContext) -> Value {
     match variable {
-        "%status%" => "queued".to_string(),
-        "%socket_token%" => socket_token.to_string(),
+        "%status%" => json!(context.status),
+        "%socket_token%" => json!(context.socket_token),
+        "%build_id%" => json!(context.build_id),
+        "%queue_position%" => json!(context.queue_position),
         var if var.starts_with('$') => {
             let env_var = &var[1..];
-            env::var(env_var).unwrap_or_else(|_| {
+            env::var(env_var).map(Value::String).unwrap_or_else(|_| {
                 // Try to get from payload
-                payload
-                    .get(env_var)
-                    .and_then(|v| v.as_str())
-                    .unwrap_or("")
-                    .to_string()
+                context.payload.get(env_var).cloned().unwrap_or(Value::Null)
             })
         }
-        var => payload
+        var if var.contains("${") => {
+            let mut values: HashMap<String, String> = context
+                .payload
+                .iter()
+                .map(|(key, value)| (key.clone(), value_to_string(value)))
+                .collect();
+            // The same build values commands and webhooks get, where the endpoint knows them
+            let build_values = [
+                ("status", Some(context.status)),
+                ("project_name", Some(context.project_name)),
+                ("unique_id", context.unique_id),
+                ("build_id", context.build_id),
+                ("socket_token", context.socket_token),
+            ];
+            for (name, value) in build_values {
+                if let Some(value) = value {
+                    values.insert(name.to_string(), value.to_string());
+                }
+            }
+            json!(interpolate(var, &values))
+        }
+        var => context
+            .payload
             .get(var)
-            .and_then(|v| v.as_str())
-            .unwrap_or(var)
-            .to_string(),
+            .cloned()
+            .unwrap_or_else(|| json!(var)),
+    }
+}
+
+/// Builds the response data from the endpoint's `return` fields, None when it has none.
+/// A field without a name uses its value without the `%` and `$` markers as the key
+pub fn resolve_return_fields(fields: &[ReturnField], context: &ResponseContext) -> Option<Value> {
+    if fields.is_empty() {
+        return None;
+    }
+
+    let mut data = serde_json::Map::new();
+    for field in fields {
+        let key = match &field.name {
+            Some(name) => name.clone(),
+            None => field
+                .value
+                .trim_matches('%')
+                .trim_start_matches('$')
+                .to_string(),
+        };
+        data.insert(key, resolve_variable(&field.value, context));
+    }
+
+    Some(Value::Object(data))
+}
+
+/// How a field listed in an endpoint `payload` is used
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PayloadKind {
+    /// `$name`: exported to the build commands as an env variable
+    Env,
+    /// `$$name`: exported like `$name` but masked wherever it would be logged
+    Secret,
+    /// `name`: only kept in the build runtime for `${name}` interpolation
+    Runtime,
+    /// `^name^`: selects a cleanup action, never exported or interpolated
+    Cleanup,
+}
+
+/// Splits a payload field like `$$git_token` into its name and kind
+pub fn parse_payload_field(field: &str) -> (&str, PayloadKind) {
+    if let Some(name) = field
+        .strip_prefix('^')
+        .and_then(|name| name.strip_suffix('^'))
+    {
+        (name, PayloadKind::Cleanup)
+    } else if let Some(name) = field.strip_prefix("$$") {
+        (name, PayloadKind::Secret)
+    } else if let Some(name) = field.strip_prefix('$') {
+        (name, PayloadKind::Env)
+    } else {
+        (field, PayloadKind::Runtime)
+    }
+}
+
+pub fn value_to_string(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        Value::Null => String::new(),
+        other => other.to_string(),
+    }
+}
+
+/// Variables a build starts with, the declared payload fields plus the build info
+pub fn build_variables(fields: &[String], request: &BuildRequest) -> BuildVariables {
+    let mut variables = payload_variables(fields, &request.payload);
+
+    let build_info = [
+        ("build_id", &request.id),
+        ("unique_id", &request.unique_id),
+        ("project_name", &request.project_name),
+        ("socket_token", &request.socket_token),
+    ];
+    for (name, value) in build_info {
+        variables.values.insert(name.to_string(), value.clone());
+    }
+
+    variables
+}
+
+/// Variables of the declared payload fields of a request
+pub fn payload_variables(fields: &[String], payload: &HashMap<String, Value>) -> BuildVariables {
+    let mut variables = BuildVariables::default();
+
+    for field in fields {
+        let (name, kind) = parse_payload_field(field);
+        let Some(value) = payload.get(name) else {
+            continue;
+        };
+        let value = value_to_string(value);
+
+        match kind {
+            PayloadKind::Env => {
+                variables.env.insert(name.to_string(), value.clone());
+            }
+            PayloadKind::Secret => {
+                variables.env.insert(name.to_string(), value.clone());
+                variables.secrets.push(value.clone());
+            }
+            PayloadKind::Runtime => {}
+            PayloadKind::Cleanup => continue,
+        }
+        variables.values.insert(name.to_string(), value);
     }
+
+    variables
+}
+
+/// Replaces every `${name}` with its value, unknown names are left untouched
+/// so shell variables like `${HOME}` keep working
+pub fn interpolate(template: &str, values: &HashMap<String, String>) -> String {
+    interpolate_with(template, values, str::to_string)
+}
+
+/// Like `interpolate`, with every value passed through `escape` first
+pub fn interpolate_with(
+    template: &str,
+    values: &HashMap<String, String>,
+    escape: impl Fn(&str) -> String,
+) -> String {
+    let mut output = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(start) = rest.find("${") {
+        output.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        match after.find('}') {
+            Some(end) => {
+                let name = &after[..end];
+                match values.get(name) {
+                    Some(value) => output.push_str(&escape(value)),
+                    None => output.push_str(&rest[start..start + 2 + end + 1]),
+                }
+                rest = &after[end + 1..];
+            }
+            None => {
+                output.push_str(&rest[start..]);
+                rest = "";
+            }
+        }
+    }
+    output.push_str(rest);
+
+    output
+}
+
+/// Fills in the `${name}` values of a command. Every value is shell-quoted, so a
+/// payload value stays a single word and cannot add commands of its own
+pub fn resolve_command(
+    command: &str,
+    payload: &HashMap<String, Value>,
+    values: &HashMap<String, String>,
+) -> String {
+    let command = command
+        .replace("${payload}", &shell_quote(&json!(payload).to_string()))
+        .replace("${timestamp}", &Utc::now().to_rfc3339());
+    interpolate_with(&command, values, shell_quote)
+}
+
+/// Single-quotes a value for bash and sh, `split_command` reads it back the same way
+pub fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', r"'\''"))
 }
 
-pub fn resolve_command(command: &str, payload: &HashMap<String, Value>) -> String {
-    command
-        .replace("${payload}", &json!(payload).to_string())
-        .replace("${timestamp}", &Utc::now().to_rfc3339())
+/// Percent-encodes everything but unreserved characters, and `/` when `keep_slash`
+pub fn url_encode(value: &str, keep_slash: bool) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (byte as char).to_string()
+            }
+            b'/' if keep_slash => "/".to_string(),
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
 }
 
+/// How long a webhook may take before it is given up, it runs after the build has finished
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(30);
+
 pub async fn send_webhook(
     webhook_url: &str,
     result: &BuildResult,
     payload: &HashMap<String, Value>,
+    fields: &[String],
+    variables: &BuildVariables,
 ) {
-    let webhook_url = webhook_url.replace("${payload}", &json!(payload).to_string());
-    let webhook_url = webhook_url.replace("${result}", &json!(result).to_string());
+    // Values are percent-encoded, so they cannot change the rest of the url
+    let webhook_url = webhook_url
+        .replace(
+            "${payload}",
+            &url_encode(&json!(payload).to_string(), false),
+        )
+        .replace("${result}", &url_encode(&json!(result).to_string(), false));
+    let webhook_url = interpolate_with(&webhook_url, &variables.values, |value| {
+        url_encode(value, false)
+    });
+
+    // Every configured field is sent as a json key, `status`, `token` and `build_id`
+    // come from the finished build and the rest from the build variables
+    let mut body = serde_json::Map::new();
+    for field in fields {
+        let (name, _) = parse_payload_field(field);
+        let value = match name {
+            "status" => json!(result.status),
+            "token" => json!(variables.values.get("socket_token")),
+            "build_id" => json!(result.id),
+            "duration" => json!(result.duration_seconds),
+            _ => variables
+                .values
+                .get(name)
+                .cloned()
+                .or_else(|| env::var(name.to_uppercase()).ok())
+                .map(Value::String)
+                .unwrap_or(Value::Null),
+        };
+        body.insert(name.to_string(), value);
+    }
+
+    let client = match Client::builder().timeout(WEBHOOK_TIMEOUT).build() {
+        Ok(client) => client,
+        Err(e) => {
+            log::error!("Failed to send webhook for build {}: {}", result.id, e);
+            return;
+        }
+    };
+    if let Err(e) = client.post(webhook_url).json(&body).send().await {
+        log::error!("Failed to send webhook for build {}: {}", result.id, e);
+    }
+}
 
-    let client = Client::new();
-    let _ = client.post(webhook_url).send().await;
+/// Where the finished builds of a project are recorded, one `BuildResult` per line
+pub fn history_file(log_path: &str, project_name: &str) -> PathBuf {
+    Path::new(log_path)
+        .join("history")
+        .join(format!("{}.jsonl", project_name))
 }
 
-pub async fn save_build_logs(log_path: &str, result: &BuildResult) {
-    let log_path = Path::new(log_path);
-    let log_file_path = log_path.join(format!("{}.log", result.id));
+/// Appends a finished build to its project's history, without the logs which have their own file
+pub async fn append_history(log_path: &str, result: &BuildResult) -> Result<(), String> {
+    let path = history_file(log_path, &result.project_name);
+    if let Some(parent) = path.parent() {
+        tokio::fs::create_dir_all(parent)
+            .await
+            .map_err(|e| format!("Failed to create {}: {}", parent.display(), e))?;
+    }
+
+    let record = BuildResult {
+        logs: Vec::new(),
+        ..result.clone()
+    };
+    let mut line = serde_json::to_string(&record).map_err(|e| e.to_string())?;
+    line.push('\n');
+
+    let mut file = tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .await
+        .map_err(|e| format!("Failed to open {}: {}", path.display(), e))?;
+    file.write_all(line.as_bytes())
+        .await
+        .map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+}
+
+/// Reads a project's recorded builds, oldest first
+pub fn load_history(log_path: &str, project_name: &str) -> Result<Vec<BuildResult>, String> {
+    let path = history_file(log_path, project_name);
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+
+    let content = fs::read_to_string(&path)
+        .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
 
-    if log_file_path.exists() {
-        fs::remove_file(&log_file_path).unwrap();
+    let mut history = Vec::new();
+    for (number, line) in content.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        // A line cut short by a crash must not keep the rest of the history from loading
+        match serde_json::from_str(line) {
+            Ok(result) => history.push(result),
+            Err(e) => log::warn!("Skipping line {} of {}: {}", number + 1, path.display(), e),
+        }
     }
 
-    fs::create_dir_all(log_path).unwrap();
+    Ok(history)
+}
 
-    let mut log_file = fs::OpenOptions::new()
+/// Where the queue and running build of a project are kept between restarts
+pub fn queue_file(log_path: &str, project_name: &str) -> PathBuf {
+    Path::new(log_path)
+        .join("state")
+        .join(format!("{}.json", project_name))
+}
+
+/// Replaces the project's queue file, through a temporary file so a crash never leaves half of it
+pub async fn save_queue(
+    log_path: &str,
+    project_name: &str,
+    persisted: &PersistedQueue,
+) -> Result<(), String> {
+    let path = queue_file(log_path, project_name);
+    if let Some(parent) = path.parent() {
+        tokio::fs::create_dir_all(parent)
+            .await
+            .map_err(|e| format!("Failed to create {}: {}", parent.display(), e))?;
+    }
+
+    // It holds the $$ secrets of every waiting build
+    let content = serde_json::to_vec(persisted).map_err(|e| e.to_string())?;
+    let temp_path = path.with_extension("json.tmp");
+    write_private(&temp_path, &content).await?;
+    tokio::fs::rename(&temp_path, &path)
+        .await
+        .map_err(|e| format!("Failed to replace {}: {}", path.display(), e))
+}
+
+pub fn load_queue(log_path: &str, project_name: &str) -> Result<PersistedQueue, String> {
+    let path = queue_file(log_path, project_name);
+    if !path.exists() {
+        return Ok(PersistedQueue::default());
+    }
+
+    let content = fs::read_to_string(&path)
+        .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    serde_json::from_str(&content).map_err(|e| format!("Invalid {}: {}", path.display(), e))
+}
+
+/// Where the uploaded files of a build wait until it has run
+pub fn spool_dir(log_path: &str, build_id: &str) -> PathBuf {
+    Path::new(log_path)
+        .join("state")
+        .join("files")
+        .join(build_id)
+}
+
+/// Writes the uploaded files of a build to its spool directory, returns where each one is
+pub async fn spool_files(
+    log_path: &str,
+    build_id: &str,
+    files: HashMap<String, Vec<u8>>,
+) -> Result<HashMap<String, PathBuf>, String> {
+    let mut spooled = HashMap::new();
+    if files.is_empty() {
+        return Ok(spooled);
+    }
+
+    let dir = spool_dir(log_path, build_id);
+    tokio::fs::create_dir_all(&dir)
+        .await
+        .map_err(|e| format!("Failed to create {}: {}", dir.display(), e))?;
+    for (name, content) in files {
+        let path = resolve_upload_path(&dir, &name)?;
+        write_private(&path, &content).await?;
+        spooled.insert(name, path);
+    }
+
+    Ok(spooled)
+}
+
+/// Deletes the spooled files of a build that will not run any more
+pub async fn remove_spooled_files(files: &HashMap<String, PathBuf>) {
+    for path in files.values() {
+        match tokio::fs::remove_file(path).await {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => log::warn!("Failed to delete {}: {}", path.display(), e),
+        }
+        // Only succeeds once the build's last file is gone
+        if let Some(dir) = path.parent() {
+            let _ = tokio::fs::remove_dir(dir).await;
+        }
+    }
+}
+
+/// Writes a file only the server's user can read, for uploads and secrets
+async fn write_private(path: &Path, content: &[u8]) -> Result<(), String> {
+    let mut file = tokio::fs::OpenOptions::new()
         .write(true)
         .create(true)
-        .open(log_file_path)
-        .unwrap();
+        .truncate(true)
+        .mode(0o600)
+        .open(path)
+        .await
+        .map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+    file.write_all(content)
+        .await
+        .map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+    file.flush()
+        .await
+        .map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+}
+
+/// Path of the log file of a build
+pub fn build_log_file(log_path: &str, build_id: &str) -> PathBuf {
+    Path::new(log_path).join(format!("{}.log", build_id))
+}
+
+/// Where the artifacts of a build are kept
+pub fn artifact_dir(log_path: &str, build_id: &str) -> PathBuf {
+    Path::new(log_path).join("artifacts").join(build_id)
+}
+
+/// Copies the files matching the `artifacts` globs from the project into `store`,
+/// blocking, so it is meant for `spawn_blocking`
+pub fn collect_artifacts(
+    workdir: &Path,
+    patterns: &[String],
+    store: &Path,
+) -> Result<Vec<Artifact>, String> {
+    let mut artifacts: Vec<Artifact> = Vec::new();
+
+    for pattern in patterns {
+        // Same rule as uploaded files, a glob cannot reach outside of the project
+        resolve_upload_path(workdir, pattern)?;
+        let full_pattern = format!(
+            "{}/{}",
+            glob::Pattern::escape(&workdir.to_string_lossy()),
+            pattern
+        );
+        let paths = glob::glob(&full_pattern)
+            .map_err(|e| format!("Invalid artifact pattern {}: {}", pattern, e))?;
+
+        for path in paths {
+            let path = path.map_err(|e| format!("Failed to read {}: {}", pattern, e))?;
+            let Ok(relative) = path.strip_prefix(workdir) else {
+                continue;
+            };
+            let name = relative.to_string_lossy().to_string();
+            if !path.is_file() || artifacts.iter().any(|artifact| artifact.name == name) {
+                continue;
+            }
+
+            let target = store.join(relative);
+            if let Some(parent) = target.parent() {
+                fs::create_dir_all(parent)
+                    .map_err(|e| format!("Failed to create {}: {}", parent.display(), e))?;
+            }
+            let size = fs::copy(&path, &target)
+                .map_err(|e| format!("Failed to copy artifact {}: {}", name, e))?;
+            let sha256 = sha256_file(&target)
+                .map_err(|e| format!("Failed to hash artifact {}: {}", name, e))?;
+            artifacts.push(Artifact { name, size, sha256 });
+        }
+    }
+
+    Ok(artifacts)
+}
+
+fn sha256_file(path: &Path) -> std::io::Result<String> {
+    let mut file = fs::File::open(path)?;
+    let mut hasher = openssl::sha::Sha256::new();
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+
+    Ok(hasher
+        .finish()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect())
+}
+
+/// Path of a build log file once the janitor compressed it
+pub fn compressed_log_file(log_path: &str, build_id: &str) -> PathBuf {
+    Path::new(log_path).join(format!("{}.log.gz", build_id))
+}
 
+/// Logs of a finished build. The history only keeps the record once the log file is
+/// written, then they are read back from it; a file that cannot be read gives no logs
+pub fn logs_of(result: &BuildResult, log_path: &str) -> Vec<BuildLog> {
+    if !result.logs.is_empty() {
+        return result.logs.clone();
+    }
+    read_build_logs(log_path, result).unwrap_or_else(|e| {
+        log::warn!("{}", e);
+        Vec::new()
+    })
+}
+
+/// Reads the logs of a finished build back from its log file
+fn read_build_logs(log_path: &str, result: &BuildResult) -> Result<Vec<BuildLog>, String> {
+    let path = build_log_file(log_path, &result.id);
+    let compressed = compressed_log_file(log_path, &result.id);
+    let content = if !path.exists() && compressed.exists() {
+        let file = fs::File::open(&compressed)
+            .map_err(|e| format!("Failed to open {}: {}", compressed.display(), e))?;
+        let mut content = String::new();
+        GzDecoder::new(file)
+            .read_to_string(&mut content)
+            .map_err(|e| format!("Failed to read {}: {}", compressed.display(), e))?;
+        content
+    } else {
+        fs::read_to_string(&path)
+            .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?
+    };
+
+    let mut logs = Vec::new();
+    for line in content.lines() {
+        if let Ok(log) = serde_json::from_str::<BuildLog>(line) {
+            logs.push(log);
+            continue;
+        }
+        if is_log_header(line) {
+            continue;
+        }
+        // Log files written before JSON lines only kept the message
+        logs.push(BuildLog {
+            seq: logs.len() as u64 + 1,
+            timestamp: result.completed_at,
+            step: 0,
+            level: LogLevel::Info,
+            message: line.to_string(),
+            command: None,
+            hidden: false,
+        });
+    }
+
+    Ok(logs)
+}
+
+fn is_log_header(line: &str) -> bool {
+    serde_json::from_str::<Value>(line)
+        .map(|value| value["type"] == "header")
+        .unwrap_or(false)
+}
+
+/// The request payload without the fields declared as `$$` secrets
+pub fn public_payload(
+    fields: &[String],
+    payload: &HashMap<String, Value>,
+) -> HashMap<String, Value> {
+    let secrets: Vec<&str> = fields
+        .iter()
+        .map(|field| parse_payload_field(field))
+        .filter(|(_, kind)| *kind == PayloadKind::Secret)
+        .map(|(name, _)| name)
+        .collect();
+
+    payload
+        .iter()
+        .filter(|(key, _)| !secrets.contains(&key.as_str()))
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .collect()
+}
+
+/// Writes a build log file as JSON lines, a header record then one record per log
+pub async fn save_build_logs(
+    log_path: &str,
+    result: &BuildResult,
+    payload: &HashMap<String, Value>,
+) -> Result<(), String> {
+    let path = build_log_file(log_path, &result.id);
+    tokio::fs::create_dir_all(log_path)
+        .await
+        .map_err(|e| format!("Failed to create {}: {}", log_path, e))?;
+
+    let header = json!({
+        "type": "header",
+        "project": result.project_name,
+        "build_id": result.id,
+        "unique_id": result.unique_id,
+        "status": result.status,
+        "started_at": result.started_at,
+        "completed_at": result.completed_at,
+        "duration_seconds": result.duration_seconds,
+        "payload": payload,
+    });
+
+    let mut content = format!("{}\n", header);
     for log in &result.logs {
-        let _ = log_file
-            .write_all(format!("{}\n", log.message).as_bytes())
-            .unwrap();
+        let line = serde_json::to_string(log)
+            .map_err(|e| format!("Failed to serialize log of {}: {}", result.id, e))?;
+        content.push_str(&line);
+        content.push('\n');
     }
+
+    tokio::fs::write(&path, content)
+        .await
+        .map_err(|e| format!("Failed to write {}: {}", path.display(), e))
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn values(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn splits_quoted_words_and_escapes() {
+        let args = split_command(r#"echo 'a  b' "c \"d\"" e\ f"#).unwrap();
+
+        assert_eq!(args, ["echo", "a  b", r#"c "d""#, "e f"]);
+    }
+
+    #[test]
+    fn rejects_an_unclosed_quote_or_a_trailing_backslash() {
+        assert!(split_command("echo 'a").is_err());
+        assert!(split_command(r#"echo "a"#).is_err());
+        assert!(split_command(r"echo a\").is_err());
+    }
+
+    #[test]
+    fn shell_quoted_values_split_back_into_one_word() {
+        for value in ["plain", "a b", "it's", "'", "", "a;b", "$(id)", r#"\"x\""#] {
+            let args = split_command(&format!("echo {}", shell_quote(value))).unwrap();
+            assert_eq!(args, ["echo", value], "{:?}", value);
+        }
+    }
+
+    #[test]
+    fn resolved_values_cannot_add_commands() {
+        let payload = HashMap::new();
+        let values = values(&[("name", "x'; touch /tmp/pwned; echo '$(id)`id`")]);
+        let command = resolve_command("echo ${name} ${HOME}", &payload, &values);
+
+        assert_eq!(
+            command,
+            r#"echo 'x'\''; touch /tmp/pwned; echo '\''$(id)`id`' ${HOME}"#
+        );
+        let args = split_command(&command).unwrap();
+        assert_eq!(
+            args,
+            ["echo", "x'; touch /tmp/pwned; echo '$(id)`id`", "${HOME}"]
+        );
+    }
+
+    #[test]
+    fn quotes_the_whole_payload_as_one_word() {
+        let payload = HashMap::from([("name".to_string(), json!("a'b; rm -rf /"))]);
+        let command = resolve_command("notify ${payload}", &payload, &HashMap::new());
+        let args = split_command(&command).unwrap();
+
+        assert_eq!(args, ["notify", r#"{"name":"a'b; rm -rf /"}"#]);
+    }
+
+    #[test]
+    fn leaves_unknown_placeholders_untouched() {
+        let values = values(&[("a", "1")]);
+
+        assert_eq!(interpolate("${a} ${b} ${a", &values), "1 ${b} ${a");
+    }
+
+    #[test]
+    fn keeps_upload_paths_inside_the_workspace() {
+        let workdir = Path::new("/srv/app");
+
+        assert_eq!(
+            resolve_upload_path(workdir, "keys/ks.jks").unwrap(),
+            Path::new("/srv/app/keys/ks.jks")
+        );
+        assert_eq!(
+            resolve_upload_path(workdir, "./out/a.txt").unwrap(),
+            Path::new("/srv/app/out/a.txt")
+        );
+        for path in ["../x", "keys/../../x", "/etc/passwd", "", "  "] {
+            assert!(resolve_upload_path(workdir, path).is_err(), "{:?}", path);
+        }
+    }
+
+    #[test]
+    fn rejects_paths_naming_the_workspace_itself() {
+        let workdir = Path::new("/srv/app");
+
+        for path in [".", "./", "././"] {
+            assert!(resolve_upload_path(workdir, path).is_err(), "{:?}", path);
+        }
+    }
+
+    #[test]
+    fn rejects_a_template_that_resolves_to_the_workspace() {
+        let workdir = Path::new("/srv/app");
+        let template = "workspaces/${package_name}";
+
+        let inside = interpolate(template, &values(&[("package_name", "com.x")]));
+        assert!(resolve_upload_path(workdir, &inside).is_ok());
+        let parent = interpolate(template, &values(&[("package_name", "..")]));
+        assert!(resolve_upload_path(workdir, &parent).is_err());
+        let empty = interpolate("./${package_name}", &values(&[("package_name", "")]));
+        assert!(resolve_upload_path(workdir, &empty).is_err());
+    }
+}
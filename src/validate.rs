@@ -0,0 +1,679 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::net::IpAddr;
+use toml_edit::{ImDocument, Item, TableLike, Value};
+
+use crate::config::{AuthConfig, CommandConfig, Config, EndpointConfig, ProjectConfig};
+use crate::utils::{self, OutputCapture, PayloadKind};
+
+/// A problem in the config file, with the line of the key it is about when it could be found
+#[derive(Debug)]
+pub struct ConfigError {
+    pub line: Option<usize>,
+    pub key: String,
+    pub message: String,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(line) = self.line {
+            write!(f, "line {}: ", line)?;
+        }
+        if !self.key.is_empty() {
+            write!(f, "{}: ", self.key)?;
+        }
+        write!(f, "{}", self.message)
+    }
+}
+
+impl ConfigError {
+    /// A TOML syntax error or a value of the wrong type
+    pub fn parse(source: &str, error: &toml::de::Error) -> Self {
+        Self {
+            line: error.span().map(|span| line_at(source, span.start)),
+            key: String::new(),
+            message: error.message().trim().to_string(),
+        }
+    }
+}
+
+const AUTH_TYPES: &[&str] = &["token", "address", "both"];
+const ADDRESS_TYPES: &[&str] = &["ip", "hostname"];
+const SHELLS: &[&str] = &["bash", "sh", "none"];
+const ON_ERROR: &[&str] = &["", "abort", "continue", "retry", "ignore", "fail_after"];
+const ON_ERR_SUC: &[&str] = &["del", "keep"];
+const CLEANUP_MARKERS: &[&str] = &["cmd", "remove"];
+/// Secret of the sample config, which is public
+const SAMPLE_SECRET: &str = "change-me-to-a-long-random-string";
+const MIN_SECRET_CHARS: usize = 8;
+
+/// Checks what deserializing cannot: allowed values, paths, and how projects and
+/// endpoints fit together. Every problem is returned, not only the first one
+pub fn validate(config: &Config, source: &str) -> Vec<ConfigError> {
+    let mut checker = Checker {
+        source,
+        document: ImDocument::parse(source).ok(),
+        errors: Vec::new(),
+    };
+
+    checker.auth(&["auth"], &config.auth);
+
+    if config.ssl.enable_ssl {
+        for (key, path) in [
+            ("certificate_path", &config.ssl.certificate_path),
+            ("certificate_key_path", &config.ssl.certificate_key_path),
+        ] {
+            if !std::path::Path::new(path).is_file() {
+                checker.error(&["ssl", key], format!("file {} does not exist", path));
+            }
+        }
+    }
+
+    if let Some(signed_urls) = &config.signed_urls {
+        let secret = &signed_urls.secret;
+        if secret.len() < 16 {
+            checker.error(
+                &["signed_urls", "secret"],
+                "must be at least 16 characters long",
+            );
+        } else if secret == SAMPLE_SECRET {
+            checker.error(
+                &["signed_urls", "secret"],
+                "is the sample value, anyone could sign links with it",
+            );
+        } else if secret.chars().collect::<HashSet<_>>().len() < MIN_SECRET_CHARS {
+            checker.error(
+                &["signed_urls", "secret"],
+                format!(
+                    "must use at least {} different characters, generate one with `openssl rand -hex 32`",
+                    MIN_SECRET_CHARS
+                ),
+            );
+        }
+        if signed_urls.max_ttl == 0 {
+            checker.error(&["signed_urls", "max_ttl"], "must be more than 0");
+        }
+        if signed_urls.default_ttl > signed_urls.max_ttl {
+            checker.error(
+                &["signed_urls", "default_ttl"],
+                format!("is longer than max_ttl ({})", signed_urls.max_ttl),
+            );
+        }
+    }
+
+    // Sorted so the same config always reports in the same order
+    let mut projects: Vec<_> = config.projects.iter().collect();
+    projects.sort_by_key(|(name, _)| name.as_str());
+
+    for (index, (name, project)) in projects.iter().enumerate() {
+        let base_path = &project.base_endpoint_path;
+        let key = ["projects", name.as_str(), "base_endpoint_path"];
+        if !base_path.starts_with('/') {
+            checker.error(&key, format!("\"{}\" must start with /", base_path));
+        }
+
+        // Requests are routed to the first project whose base path prefixes them
+        for (other_name, other) in &projects[index + 1..] {
+            let other_path = &other.base_endpoint_path;
+            if base_path == other_path {
+                checker.error(
+                    &key,
+                    format!("\"{}\" is also used by project {}", base_path, other_name),
+                );
+            } else if other_path.starts_with(base_path.as_str())
+                || base_path.starts_with(other_path.as_str())
+            {
+                checker.error(
+                    &key,
+                    format!(
+                        "\"{}\" overlaps \"{}\" of project {}",
+                        base_path, other_path, other_name
+                    ),
+                );
+            }
+        }
+
+        checker.project(config, name, project);
+    }
+
+    checker.errors
+}
+
+struct Checker<'a> {
+    source: &'a str,
+    document: Option<ImDocument<&'a str>>,
+    errors: Vec<ConfigError>,
+}
+
+impl Checker<'_> {
+    fn error(&mut self, path: &[&str], message: impl Into<String>) {
+        let line = self
+            .document
+            .as_ref()
+            .and_then(|document| find_line(document.as_table(), path, self.source, None));
+        let key = path
+            .iter()
+            .map(|segment| match segment.parse::<usize>() {
+                Ok(index) => format!("[{}]", index),
+                Err(_) => format!(".{}", segment),
+            })
+            .collect::<String>();
+
+        self.errors.push(ConfigError {
+            line,
+            key: key.trim_start_matches('.').to_string(),
+            message: message.into(),
+        });
+    }
+
+    fn auth(&mut self, path: &[&str], auth: &AuthConfig) {
+        let key = |name: &'static str| [path, &[name]].concat();
+
+        if !AUTH_TYPES.contains(&auth.auth_type.as_str()) {
+            self.error(
+                &key("auth_type"),
+                format!(
+                    "unknown \"{}\", expected {}",
+                    auth.auth_type,
+                    AUTH_TYPES.join(", ")
+                ),
+            );
+        }
+        if !ADDRESS_TYPES.contains(&auth.address_type.as_str()) {
+            self.error(
+                &key("address_type"),
+                format!(
+                    "unknown \"{}\", expected {}",
+                    auth.address_type,
+                    ADDRESS_TYPES.join(", ")
+                ),
+            );
+        }
+
+        // An empty list makes every request unauthorized
+        let uses_tokens = matches!(auth.auth_type.as_str(), "token" | "both");
+        let uses_addresses = matches!(auth.auth_type.as_str(), "address" | "both");
+        if uses_tokens && auth.allowed_tokens.is_empty() {
+            self.error(
+                &key("allowed_tokens"),
+                "is empty, every request would be refused",
+            );
+        }
+        if uses_addresses && auth.allowed_addresses.is_empty() {
+            self.error(
+                &key("allowed_addresses"),
+                "is empty, every request would be refused",
+            );
+        }
+        if uses_addresses && auth.address_type == "ip" {
+            for address in &auth.allowed_addresses {
+                if address.parse::<IpAddr>().is_err() {
+                    self.error(
+                        &key("allowed_addresses"),
+                        format!("\"{}\" is not an ip address", address),
+                    );
+                }
+            }
+        }
+    }
+
+    fn project(&mut self, config: &Config, name: &str, project: &ProjectConfig) {
+        if let Some(auth) = &project.auth {
+            self.auth(&project_key(name, &["auth"]), auth);
+        }
+
+        let api = &project.api;
+        let endpoints = [
+            ("build", Some(&api.build)),
+            ("is_building", Some(&api.is_building)),
+            ("abort", Some(&api.abort)),
+            ("cleanup", Some(&api.cleanup)),
+            ("socket", Some(&api.socket)),
+            ("observe", api.observe.as_ref()),
+            ("history", api.history.as_ref()),
+            ("artifacts", api.artifacts.as_ref()),
+            ("sign", api.sign.as_ref()),
+        ];
+        for (endpoint_name, endpoint) in endpoints {
+            if let Some(endpoint) = endpoint {
+                self.endpoint(&project_key(name, &["api", endpoint_name]), endpoint);
+            }
+        }
+
+        let build = &project.build;
+        let workdir = utils::resolve_project_path(&config.base_path, &build.project_path);
+        if !workdir.is_dir() {
+            self.error(
+                &project_key(name, &["build", "project_path"]),
+                format!("directory {} does not exist", workdir.display()),
+            );
+        }
+
+        let payload_names: Vec<&str> = api
+            .build
+            .payload
+            .iter()
+            .map(|field| utils::parse_payload_field(field).0)
+            .collect();
+        if !payload_names.contains(&build.unique_build_key.as_str()) {
+            self.error(
+                &project_key(name, &["build", "unique_build_key"]),
+                format!(
+                    "\"{}\" is not in the build payload {:?}",
+                    build.unique_build_key, api.build.payload
+                ),
+            );
+        }
+
+        if let Some(shell) = &build.shell {
+            self.shell(&project_key(name, &["build", "shell"]), shell);
+        }
+        if build.commands.is_empty() {
+            self.error(
+                &project_key(name, &["build", "commands"]),
+                "a build needs at least one command",
+            );
+        }
+        for (list, commands) in [
+            ("commands", &build.commands),
+            ("run_on_success", &build.run_on_success),
+            ("run_on_failure", &build.run_on_failure),
+        ] {
+            for (index, command) in commands.iter().enumerate() {
+                let index = index.to_string();
+                self.command(
+                    &[&project_key(name, &["build", list])[..], &[index.as_str()]].concat(),
+                    command,
+                );
+            }
+        }
+
+        for pattern in &build.artifacts {
+            let valid = utils::resolve_upload_path(&workdir, pattern)
+                .and_then(|_| glob::Pattern::new(pattern).map_err(|e| e.to_string()));
+            if let Err(e) = valid {
+                self.error(
+                    &project_key(name, &["build", "artifacts"]),
+                    format!("\"{}\": {}", pattern, e),
+                );
+            }
+        }
+
+        // Each path belongs to one build key, a template without it would remove
+        // what every build shares
+        let unique_key = format!("${{{}}}", build.unique_build_key);
+        for template in &project.cleanup.remove {
+            if let Err(e) = utils::resolve_upload_path(&workdir, template) {
+                self.error(&project_key(name, &["cleanup", "remove"]), e);
+            } else if !template.contains(&unique_key) && !template.contains("${unique_id}") {
+                self.error(
+                    &project_key(name, &["cleanup", "remove"]),
+                    format!("\"{}\" must contain {}", template, unique_key),
+                );
+            }
+        }
+        for (command_name, command) in &project.cleanup.commands {
+            self.command(
+                &project_key(name, &["cleanup", "commands", command_name]),
+                command,
+            );
+        }
+        for field in &api.cleanup.payload {
+            let (marker, kind) = utils::parse_payload_field(field);
+            if kind == PayloadKind::Cleanup && !CLEANUP_MARKERS.contains(&marker) {
+                self.error(
+                    &project_key(name, &["api", "cleanup", "payload"]),
+                    format!("unknown marker \"{}\", expected ^cmd^ or ^remove^", field),
+                );
+            }
+        }
+
+        for (index, file) in api.build.file.iter().enumerate() {
+            let index = index.to_string();
+            let file_key = [
+                &project_key(name, &["api", "build", "file"])[..],
+                &[index.as_str()],
+            ]
+            .concat();
+            if !ON_ERR_SUC.contains(&file.on_err_suc.as_str()) {
+                self.error(
+                    &[&file_key[..], &["on_err_suc"]].concat(),
+                    format!("unknown \"{}\", expected del or keep", file.on_err_suc),
+                );
+            }
+            if let Err(e) = utils::resolve_upload_path(&workdir, &file.path) {
+                self.error(&[&file_key[..], &["path"]].concat(), e);
+            }
+        }
+    }
+
+    fn endpoint(&mut self, path: &[&str], endpoint: &EndpointConfig) {
+        let trimmed = endpoint.endpoint.trim();
+        if !trimmed.is_empty() && !trimmed.starts_with('/') {
+            self.error(
+                &[path, &["endpoint"]].concat(),
+                format!("\"{}\" must start with /", endpoint.endpoint),
+            );
+        }
+    }
+
+    fn command(&mut self, path: &[&str], command: &CommandConfig) {
+        if command.command.trim().is_empty() {
+            self.error(&[path, &["command"]].concat(), "is empty");
+        }
+        if !ON_ERROR.contains(&command.on_error.as_str()) {
+            self.error(
+                &[path, &["on_error"]].concat(),
+                format!(
+                    "unknown \"{}\", expected {}",
+                    command.on_error,
+                    ON_ERROR[1..].join(", ")
+                ),
+            );
+        }
+        if let Some(shell) = &command.shell {
+            self.shell(&[path, &["shell"]].concat(), shell);
+        }
+        if let Err(e) = OutputCapture::new(command) {
+            self.error(&[path, &["capture_mode"]].concat(), e);
+        }
+    }
+
+    fn shell(&mut self, path: &[&str], shell: &str) {
+        if !SHELLS.contains(&shell) {
+            self.error(
+                path,
+                format!("unknown \"{}\", expected {}", shell, SHELLS.join(", ")),
+            );
+        }
+    }
+}
+
+fn project_key<'a>(name: &'a str, rest: &[&'a str]) -> Vec<&'a str> {
+    [&["projects", name][..], rest].concat()
+}
+
+/// Line of the deepest key of `path` found in the document, array entries are numeric segments
+fn find_line(
+    table: &dyn TableLike,
+    path: &[&str],
+    source: &str,
+    found: Option<usize>,
+) -> Option<usize> {
+    let Some((first, rest)) = path.split_first() else {
+        return found;
+    };
+    let Some((key, item)) = table.get_key_value(first) else {
+        return found;
+    };
+    let found = key
+        .span()
+        .or_else(|| item.span())
+        .map(|span| line_at(source, span.start))
+        .or(found);
+
+    let index = rest
+        .first()
+        .and_then(|segment| segment.parse::<usize>().ok());
+    match (item, index) {
+        (Item::Table(table), _) => find_line(table, rest, source, found),
+        (Item::Value(Value::InlineTable(table)), _) => find_line(table, rest, source, found),
+        (Item::ArrayOfTables(tables), Some(index)) => match tables.get(index) {
+            Some(table) => {
+                let found = table
+                    .span()
+                    .map(|span| line_at(source, span.start))
+                    .or(found);
+                find_line(table, &rest[1..], source, found)
+            }
+            None => found,
+        },
+        (Item::Value(Value::Array(values)), Some(index)) => match values.get(index) {
+            Some(Value::InlineTable(table)) => find_line(table, &rest[1..], source, found),
+            Some(value) => value
+                .span()
+                .map(|span| line_at(source, span.start))
+                .or(found),
+            None => found,
+        },
+        _ => found,
+    }
+}
+
+fn line_at(source: &str, offset: usize) -> usize {
+    source[..offset.min(source.len())].matches('\n').count() + 1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"name = "test"
+port = 8080
+base_path = ""
+log_path = "logs"
+
+[ssl]
+enable_ssl = false
+certificate_path = ""
+certificate_key_path = ""
+
+[auth]
+auth_type = "token"
+address_type = "ip"
+allowed_addresses = []
+allowed_tokens = ["secret"]
+
+[projects.app]
+allow_multi_build = true
+max_pending_build = 10
+base_endpoint_path = "/api/app"
+
+[projects.app.api]
+build = { endpoint = "/build", method = "POST", payload = ["$package_name"] }
+is_building = { endpoint = "/is_building", method = "GET", payload = [] }
+abort = { endpoint = "/abort", method = "POST", payload = [] }
+cleanup = { endpoint = "/cleanup", method = "POST", payload = [] }
+socket = { endpoint = "/connect", method = "GET", payload = [] }
+
+[projects.app.build]
+project_path = "."
+unique_build_key = "package_name"
+on_success = ""
+on_failure = ""
+on_success_payload = []
+on_failure_payload = []
+
+[[projects.app.build.commands]]
+command = "echo one"
+title = "One"
+
+[[projects.app.build.commands]]
+command = "echo two"
+title = "Two"
+on_error = "continue"
+"#;
+
+    fn check(source: &str) -> Vec<ConfigError> {
+        let config: Config = toml::from_str(source).expect("test config deserializes");
+        validate(&config, source)
+    }
+
+    fn only_error(source: &str) -> ConfigError {
+        let mut errors = check(source);
+        assert_eq!(errors.len(), 1, "{:?}", errors);
+        errors.remove(0)
+    }
+
+    #[test]
+    fn accepts_a_valid_config() {
+        assert!(check(CONFIG).is_empty(), "{:?}", check(CONFIG));
+    }
+
+    #[test]
+    fn reports_an_unknown_auth_type_at_its_line() {
+        let source = CONFIG.replace(r#"auth_type = "token""#, r#"auth_type = "tokens""#);
+        let error = only_error(&source);
+
+        assert_eq!(error.line, Some(12));
+        assert_eq!(error.key, "auth.auth_type");
+        assert!(error.message.contains("\"tokens\""), "{}", error.message);
+    }
+
+    #[test]
+    fn reports_an_empty_token_list() {
+        let source = CONFIG.replace(r#"allowed_tokens = ["secret"]"#, "allowed_tokens = []");
+        let error = only_error(&source);
+
+        assert_eq!(error.line, Some(15));
+        assert_eq!(error.key, "auth.allowed_tokens");
+    }
+
+    #[test]
+    fn reports_overlapping_base_paths() {
+        let second = CONFIG[CONFIG.find("[projects.app]").unwrap()..]
+            .replace("projects.app", "projects.app_v2")
+            .replace("/api/app", "/api/app/v2");
+        let source = format!("{}\n{}", CONFIG, second);
+        let error = only_error(&source);
+
+        assert_eq!(error.line, Some(20));
+        assert_eq!(error.key, "projects.app.base_endpoint_path");
+        assert!(error.message.contains("overlaps"), "{}", error.message);
+    }
+
+    #[test]
+    fn reports_a_base_path_without_a_leading_slash() {
+        let source = CONFIG.replace(r#""/api/app""#, r#""api/app""#);
+        let error = only_error(&source);
+
+        assert_eq!(error.line, Some(20));
+        assert_eq!(error.key, "projects.app.base_endpoint_path");
+    }
+
+    #[test]
+    fn reports_a_bad_on_error_inside_an_array_of_tables() {
+        let source = CONFIG.replace(r#"on_error = "continue""#, r#"on_error = "skip""#);
+        let error = only_error(&source);
+
+        assert_eq!(error.line, Some(44));
+        assert_eq!(error.key, "projects.app.build.commands[1].on_error");
+        assert!(error.message.contains("\"skip\""), "{}", error.message);
+    }
+
+    #[test]
+    fn reports_an_endpoint_inside_an_inline_table() {
+        let source = CONFIG.replace(r#"endpoint = "/abort""#, r#"endpoint = "abort""#);
+        let error = only_error(&source);
+
+        assert_eq!(error.line, Some(25));
+        assert_eq!(error.key, "projects.app.api.abort.endpoint");
+    }
+
+    #[test]
+    fn reports_a_unique_key_missing_from_the_payload() {
+        let source = CONFIG.replace(
+            r#"unique_build_key = "package_name""#,
+            r#"unique_build_key = "id""#,
+        );
+        let error = only_error(&source);
+
+        assert_eq!(error.line, Some(31));
+        assert_eq!(error.key, "projects.app.build.unique_build_key");
+    }
+
+    #[test]
+    fn reports_every_problem_at_once() {
+        let source = CONFIG
+            .replace(r#"address_type = "ip""#, r#"address_type = "mac""#)
+            .replace(r#"on_error = "continue""#, r#"on_error = "skip""#);
+        let keys: Vec<String> = check(&source).into_iter().map(|error| error.key).collect();
+
+        assert_eq!(
+            keys,
+            [
+                "auth.address_type",
+                "projects.app.build.commands[1].on_error"
+            ]
+        );
+    }
+
+    fn with_remove(templates: &str) -> String {
+        format!(
+            "{}\n[projects.app.cleanup]\nremove = {}\n",
+            CONFIG, templates
+        )
+    }
+
+    #[test]
+    fn accepts_remove_paths_of_the_unique_key() {
+        let source = with_remove(r#"["workspaces/${package_name}", "out/${unique_id}.apk"]"#);
+        assert!(check(&source).is_empty(), "{:?}", check(&source));
+    }
+
+    #[test]
+    fn rejects_a_remove_path_of_the_whole_project() {
+        for template in [r#"".""#, r#""./""#, r#""${package_name}/..""#] {
+            let error = only_error(&with_remove(&format!("[{}]", template)));
+
+            assert_eq!(error.key, "projects.app.cleanup.remove", "{}", template);
+            assert_eq!(error.line, Some(47));
+        }
+    }
+
+    #[test]
+    fn rejects_a_remove_path_without_the_unique_key() {
+        let error = only_error(&with_remove(r#"["workspaces/${package_name}", "build"]"#));
+
+        assert_eq!(error.key, "projects.app.cleanup.remove");
+        assert!(
+            error.message.contains("${package_name}"),
+            "{}",
+            error.message
+        );
+    }
+
+    fn with_secret(secret: &str) -> String {
+        CONFIG.replace(
+            "[auth]",
+            &format!("[signed_urls]\nsecret = \"{}\"\n\n[auth]", secret),
+        )
+    }
+
+    #[test]
+    fn accepts_a_random_signing_secret() {
+        let source = with_secret("3f9c1a7e0b5d28c46e1f9a3b7d0c5e82");
+        assert!(check(&source).is_empty(), "{:?}", check(&source));
+    }
+
+    #[test]
+    fn rejects_the_sample_signing_secret() {
+        let error = only_error(&with_secret(SAMPLE_SECRET));
+
+        assert_eq!(error.line, Some(12));
+        assert_eq!(error.key, "signed_urls.secret");
+    }
+
+    #[test]
+    fn rejects_a_signing_secret_of_few_characters() {
+        let error = only_error(&with_secret("abababababababababababab"));
+
+        assert_eq!(error.key, "signed_urls.secret");
+        assert!(
+            error.message.contains("different characters"),
+            "{}",
+            error.message
+        );
+    }
+
+    #[test]
+    fn reports_the_line_of_a_type_error() {
+        let source = CONFIG.replace("port = 8080", r#"port = "8080""#);
+        let error = toml::from_str::<Config>(&source)
+            .map(|_| ())
+            .expect_err("a string port does not deserialize");
+        let error = ConfigError::parse(&source, &error);
+
+        assert_eq!(error.line, Some(2));
+    }
+}
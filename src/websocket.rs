@@ -1,9 +1,22 @@
 use actix_web::{Error, HttpRequest, HttpResponse, get, web};
-use actix_ws::handle;
+use actix_ws::{Message, handle};
+use futures_util::StreamExt;
 use serde_json::json;
+use std::time::{Duration, Instant};
 use tokio::sync::broadcast;
 
-use crate::{handlers::extract_project_name, models::{AppState, ServerMessage, WebSocketQuery}};
+use crate::{
+    auth::is_authorized,
+    build::BuildManager,
+    handlers::extract_project_name,
+    models::{AppState, ClientMessage, LogLevel, ServerMessage, SocketMessage, WebSocketQuery},
+    utils,
+};
+
+/// How often the server pings a build socket
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(15);
+/// A build socket that sent nothing, not even a pong, for this long is closed
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(45);
 
 pub async fn websocket_handler(
     req: HttpRequest,
@@ -12,70 +25,366 @@ pub async fn websocket_handler(
     query: web::Query<WebSocketQuery>,
 ) -> Result<HttpResponse, Error> {
     let token = &query.token;
-    let project_name = extract_project_name(&req, &state.config)?; 
-    
+    let since = query.since;
+    let project_name = extract_project_name(&req, &state.config)?;
+    // Aborting from the socket follows the rules of the HTTP abort endpoint
+    let can_abort = is_authorized(&req, &state, Some(&project_name)).await;
 
-    // Validate token exists in any current build
-    let projects = state.projects.read().await;
-    let mut token_valid = false;
+    let Some(build_stream) = open_build_stream(&state, &project_name, token, since).await else {
+        return Ok(HttpResponse::BadRequest().json(json!({
+            "error": "Invalid or expired token"
+        })));
+    };
+
+    let (greeting, mut receiver) = match build_stream {
+        BuildStream::Live { greeting, receiver } => (greeting, receiver),
+        BuildStream::Finished { replay } => {
+            let (res, mut session, _msg_stream) = handle(&req, stream)?;
+            actix_web::rt::spawn(async move {
+                for message in replay {
+                    let _ = session.text(message.to_json(None)).await;
+                }
+                let _ = session.close(None).await;
+            });
+            return Ok(res);
+        }
+    };
+
+    let (res, mut session, mut msg_stream) = handle(&req, stream)?;
+
+    for message in greeting {
+        let _ = session.text(message.to_json(None)).await;
+    }
 
-    let project_state = projects.get(&project_name).unwrap();
+    // Handle incoming messages
+    let token = token.clone();
+    let state = state.clone();
+
+    actix_web::rt::spawn(async move {
+        let mut filter = SubscribeFilter::default();
+        let mut heartbeat = tokio::time::interval(HEARTBEAT_INTERVAL);
+        let mut last_seen = Instant::now();
+
+        loop {
+            tokio::select! {
+                message = receiver.recv() => match message {
+                    Ok(ServerMessage::Message(message)) => {
+                        if filter.allows(&message) && session.text(message.to_json(None)).await.is_err() {
+                            break;
+                        }
+                    }
+                    Ok(ServerMessage::Data(data)) => {
+                        if session.text(data).await.is_err() {
+                            break;
+                        }
+                    }
+                    Ok(ServerMessage::Shutdown) => {
+                        if let Err(e) = session.close(None).await {
+                            log::error!("Error closing websocket: {}", e);
+                        }
+                        break; // shutdown signal received
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        // The client can fill the gap by reconnecting with the last seq it got
+                        let error = SocketMessage::Error {
+                            message: format!("Missed {} messages, reconnect with since", skipped),
+                        };
+                        let _ = session.text(error.to_json(None)).await;
+                    }
+                },
+                incoming = msg_stream.next() => {
+                    let Some(Ok(incoming)) = incoming else {
+                        break; // client went away
+                    };
+                    last_seen = Instant::now();
+
+                    match incoming {
+                        Message::Text(text) => {
+                            let reply = handle_client_message(&state, &project_name, &token, can_abort, &text, &mut filter).await;
+                            if session.text(reply.to_json(None)).await.is_err() {
+                                break;
+                            }
+                        }
+                        Message::Ping(bytes) if session.pong(&bytes).await.is_err() => break,
+                        Message::Close(reason) => {
+                            let _ = session.close(reason).await;
+                            break;
+                        }
+                        _ => {}
+                    }
+                }
+                _ = heartbeat.tick() => {
+                    if last_seen.elapsed() > CLIENT_TIMEOUT {
+                        log::info!("WebSocket idle for too long, closing for token: {}", token);
+                        let _ = session.close(None).await;
+                        break;
+                    }
+                    if session.ping(b"").await.is_err() {
+                        break;
+                    }
+                }
+            }
+        } //loop
+
+        log::info!("WebSocket connection closed for token: {}", token);
+    });
+
+    Ok(res)
+}
+
+/// Where a build socket or event stream starts from
+pub enum BuildStream {
+    /// Running or queued build, the greeting is followed by what the receiver gets
+    Live {
+        greeting: Vec<SocketMessage>,
+        receiver: broadcast::Receiver<ServerMessage>,
+    },
+    /// Finished build, everything is in the replay
+    Finished { replay: Vec<SocketMessage> },
+}
+
+/// Looks the socket token up in the running build, the queue and then the history,
+/// None when no build has it
+pub async fn open_build_stream(
+    state: &AppState,
+    project_name: &str,
+    token: &str,
+    since: u64,
+) -> Option<BuildStream> {
+    // The token may belong to the running build or to one still waiting in the queue
+    let projects = state.projects.read().await;
+    let project_state = projects.get(project_name)?;
 
     let current_build = project_state.current_build.lock().await;
-    if let Some(build) = current_build.as_ref() {
-        if build.socket_token == *token {
-            token_valid = true;
+    let queue = project_state.build_queue.lock().await;
+    let running = current_build
+        .as_ref()
+        .filter(|build| build.socket_token == token);
+    let queued = queue.iter().position(|build| build.socket_token == token);
+
+    if running.is_none() && queued.is_none() {
+        drop(queue);
+        drop(current_build);
+
+        // A finished build replays its logs from history
+        let history = project_state.build_history.lock().await;
+        let result = history
+            .iter()
+            .rev()
+            .find(|result| result.socket_token == token)?;
+        let logs = utils::logs_of(result, state.config.project_log_path(project_name));
+
+        let replay = vec![
+            SocketMessage::Hello {
+                build_id: result.id.clone(),
+                state: "finished".to_string(),
+            },
+            SocketMessage::Snapshot {
+                build_id: result.id.clone(),
+                status: result.status.clone(),
+                current_step: result.steps.last().map_or(0, |step| step.step),
+                total_steps: result.steps.len(),
+                logs: logs
+                    .into_iter()
+                    .filter(|log| !log.hidden && log.seq > since)
+                    .collect(),
+            },
+            SocketMessage::BuildFinished {
+                build_id: result.id.clone(),
+                unique_id: result.unique_id.clone(),
+                status: result.status.clone(),
+                duration_seconds: result.duration_seconds,
+            },
+        ];
+        return Some(BuildStream::Finished { replay });
+    }
+
+    // Listen only on this build's channel, subscribed while the build is locked so it cannot end in between
+    let receiver = state.websocket_manager.subscribe(token).await?;
+
+    // The logs after `since` for this token, or where it waits in the queue
+    let greeting = if let Some(build) = running {
+        vec![
+            SocketMessage::Hello {
+                build_id: build.id.clone(),
+                state: "running".to_string(),
+            },
+            SocketMessage::Snapshot {
+                build_id: build.id.clone(),
+                status: build.status.clone(),
+                current_step: build.current_step,
+                total_steps: build.total_steps,
+                logs: build
+                    .logs
+                    .iter()
+                    .filter(|log| !log.hidden && log.seq > since)
+                    .cloned()
+                    .collect(),
+            },
+        ]
+    } else {
+        let pos = queued.unwrap();
+        vec![
+            SocketMessage::Hello {
+                build_id: queue[pos].id.clone(),
+                state: "queued".to_string(),
+            },
+            SocketMessage::QueuePosition {
+                build_id: queue[pos].id.clone(),
+                position: pos + 1,
+                queue_length: queue.len(),
+            },
+        ]
+    };
+
+    Some(BuildStream::Live { greeting, receiver })
+}
+
+/// What a client asked for with `subscribe`, logs and steps outside of it are skipped
+#[derive(Default)]
+struct SubscribeFilter {
+    steps: Option<Vec<usize>>,
+    levels: Option<Vec<LogLevel>>,
+}
+
+impl SubscribeFilter {
+    fn allows(&self, message: &SocketMessage) -> bool {
+        let step_allowed = |step: usize| {
+            self.steps
+                .as_ref()
+                .is_none_or(|steps| steps.contains(&step))
+        };
+        match message {
+            SocketMessage::Log { log, .. } => {
+                step_allowed(log.step)
+                    && self
+                        .levels
+                        .as_ref()
+                        .is_none_or(|levels| levels.contains(&log.level))
+            }
+            SocketMessage::StepStarted { step, .. } => step_allowed(*step),
+            SocketMessage::StepFinished { result, .. } => step_allowed(result.step),
+            _ => true,
         }
     }
-    else{
-        return Ok(HttpResponse::BadRequest().json(json!({
-            "error": "No any build in progress"
-        })));
+}
+
+/// Runs a command sent by the client and returns the reply for it
+async fn handle_client_message(
+    state: &AppState,
+    project_name: &str,
+    token: &str,
+    can_abort: bool,
+    text: &str,
+    filter: &mut SubscribeFilter,
+) -> SocketMessage {
+    let ack = |command: &str| SocketMessage::Ack {
+        command: command.to_string(),
+    };
+
+    match serde_json::from_str::<ClientMessage>(text) {
+        Ok(ClientMessage::Ping { id }) => SocketMessage::Pong { id },
+        Ok(ClientMessage::Subscribe { steps, levels }) => {
+            *filter = SubscribeFilter { steps, levels };
+            ack("subscribe")
+        }
+        Ok(ClientMessage::Abort) if !can_abort => SocketMessage::Error {
+            message: "Unauthorized".to_string(),
+        },
+        Ok(ClientMessage::Abort) => {
+            if BuildManager::abort_by_token(state, project_name, token).await {
+                ack("abort")
+            } else {
+                SocketMessage::Error {
+                    message: "No build to abort".to_string(),
+                }
+            }
+        }
+        Err(e) => SocketMessage::Error {
+            message: format!("Invalid message: {}", e),
+        },
     }
+}
 
-    if !token_valid {
-        return Ok(HttpResponse::BadRequest().json(json!({
-            "error": "Invalid or expired token"
+/// Observer socket of a project, streams the events of every build of it
+pub async fn project_observer_handler(
+    req: HttpRequest,
+    stream: web::Payload,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse, Error> {
+    let project_name = extract_project_name(&req, &state.config)?;
+
+    if !is_authorized(&req, &state, Some(&project_name)).await {
+        return Ok(HttpResponse::Unauthorized().json(json!({
+            "error": "Unauthorized"
         })));
     }
 
-    let (res, mut session, _msg_stream) = handle(&req, stream)?;
+    let projects = state.projects.read().await;
+    let receiver = projects
+        .get(&project_name)
+        .unwrap()
+        .event_sender
+        .subscribe();
+    drop(projects);
 
-    // Add connection to manager and get receiver
-    let mut receiver = state.build_sender.subscribe();
+    stream_events(&req, stream, receiver)
+}
 
-    // Send any existing logs for this token
-   
-    if let Some(build) = current_build.as_ref() {
-        let json_array = serde_json::to_string(&*build.logs).unwrap();
-        // for line in buf.iter() {
-        let _ = session.text(json_array).await;
+/// Observer socket of the server, streams the events of every project
+#[get("/observe")]
+pub async fn server_observer_handler(
+    req: HttpRequest,
+    stream: web::Payload,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse, Error> {
+    if !is_authorized(&req, &state, None).await {
+        return Ok(HttpResponse::Unauthorized().json(json!({
+            "error": "Unauthorized"
+        })));
     }
 
-    drop(current_build);
+    stream_events(&req, stream, state.project_sender.subscribe())
+}
 
-    // Handle incoming messages
-    let token_clone = token.clone();
+fn stream_events(
+    req: &HttpRequest,
+    stream: web::Payload,
+    mut receiver: broadcast::Receiver<ServerMessage>,
+) -> Result<HttpResponse, Error> {
+    let (res, mut session, _msg_stream) = handle(req, stream)?;
 
     actix_web::rt::spawn(async move {
         loop {
             match receiver.recv().await {
                 Ok(ServerMessage::Data(data)) => {
-                    let _ = session.text(data).await;
+                    // Stop once the observer went away
+                    if session.text(data).await.is_err() {
+                        break;
+                    }
                 }
-                Ok(ServerMessage::Shutdown) => {
-                    if let Err(e) = session.close(None).await {
-                        log::error!("Error closing websocket: {}", e);
+                Ok(ServerMessage::Message(message)) => {
+                    if session.text(message.to_json(None)).await.is_err() {
+                        break;
                     }
-                    break; // shutdown signal received
                 }
-                Err(broadcast::error::RecvError::Closed) => break,
-                Err(_) => continue, // Lagged or other
+                Ok(ServerMessage::Shutdown) | Err(broadcast::error::RecvError::Closed) => {
+                    let _ = session.close(None).await;
+                    break;
+                }
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    log::warn!("Observer socket skipped {} events", skipped);
+                }
             }
-        } //loop
-
-        log::info!("WebSocket connection closed for token: {}", token_clone);
+        }
     });
 
     Ok(res)
 }
+
+/// Describes the messages the sockets send
+#[get("/protocol")]
+pub async fn protocol_schema() -> HttpResponse {
+    HttpResponse::Ok().json(SocketMessage::schema())
+}